@@ -0,0 +1,14 @@
+//! Like `decode_message`, but compresses the fuzz input first,
+//! so that the fuzzer spends its time on the bincode deserialization.
+//!
+//! Run with `cargo +nightly fuzz run decode_bincode` from the `eterm` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bincoded: &[u8]| {
+    let packet = zstd::encode_all(bincoded, 0).unwrap();
+    let _ = eterm::decode_message::<eterm::ClientToServerMessage>(&packet);
+    let _ = eterm::decode_message::<eterm::ServerToClientMessage>(&packet);
+});
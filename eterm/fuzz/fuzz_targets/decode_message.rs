@@ -0,0 +1,12 @@
+//! Feed arbitrary packets to [`eterm::decode_message`].
+//!
+//! Run with `cargo +nightly fuzz run decode_message` from the `eterm` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|packet: &[u8]| {
+    let _ = eterm::decode_message::<eterm::ClientToServerMessage>(packet);
+    let _ = eterm::decode_message::<eterm::ServerToClientMessage>(packet);
+});
@@ -0,0 +1,100 @@
+//! Restricting which addresses may connect to the server.
+
+use ipnet::IpNet;
+use parking_lot::RwLock;
+use std::{net::IpAddr, sync::Arc};
+
+/// CIDR allow and deny lists of which peers may connect to a server.
+///
+/// A peer is rejected if it matches any entry in the deny list.
+/// Otherwise it is accepted if the allow list is empty or it matches any entry in it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Parse the lists from CIDR notation, e.g. `"10.0.0.0/8"` or `"::1/128"`.
+    ///
+    /// ```
+    /// let access_list = eterm::AccessList::parse(&["10.0.0.0/8"], &["10.0.0.13/32"]).unwrap();
+    /// assert!(access_list.is_allowed("10.1.2.3".parse().unwrap()));
+    /// assert!(!access_list.is_allowed("10.0.0.13".parse().unwrap()));
+    /// assert!(!access_list.is_allowed("192.168.0.1".parse().unwrap()));
+    /// ```
+    ///
+    /// # Errors
+    /// On malformed CIDR.
+    pub fn parse(allow: &[&str], deny: &[&str]) -> anyhow::Result<Self> {
+        fn parse_list(list: &[&str]) -> anyhow::Result<Vec<IpNet>> {
+            list.iter()
+                .map(|cidr| {
+                    cidr.parse()
+                        .map_err(|err| anyhow::anyhow!("Bad CIDR {:?}: {}", cidr, err))
+                })
+                .collect()
+        }
+
+        Ok(Self {
+            allow: parse_list(allow)?,
+            deny: parse_list(deny)?,
+        })
+    }
+
+    /// May a peer with this address connect?
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // Treat IPv4 clients of a dual-stack socket as IPv4:
+        let ip = match ip {
+            IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// A handle to the [`AccessList`] of a server, which can be updated at runtime,
+/// e.g. from a thread watching a config file.
+///
+/// Connected clients that are no longer allowed are disconnected.
+#[derive(Clone, Default)]
+pub struct SharedAccessList(Arc<RwLock<AccessList>>);
+
+impl SharedAccessList {
+    /// Replace the access list.
+    pub fn set(&self, access_list: AccessList) {
+        tracing::info!("eterm access list updated: {:?}", access_list);
+        *self.0.write() = access_list;
+    }
+
+    /// The current access list.
+    pub fn get(&self) -> AccessList {
+        self.0.read().clone()
+    }
+
+    /// May a peer with this address connect?
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.0.read().is_allowed(ip)
+    }
+}
+
+#[test]
+fn test_access_list() {
+    let everyone = AccessList::default();
+    assert!(everyone.is_allowed("1.2.3.4".parse().unwrap()));
+
+    let access_list =
+        AccessList::parse(&["192.168.0.0/16", "fd00::/8"], &["192.168.1.0/24"]).unwrap();
+    assert!(access_list.is_allowed("192.168.0.5".parse().unwrap()));
+    assert!(!access_list.is_allowed("192.168.1.5".parse().unwrap()));
+    assert!(!access_list.is_allowed("10.0.0.1".parse().unwrap()));
+    assert!(access_list.is_allowed("fd12::1".parse().unwrap()));
+    assert!(access_list.is_allowed("::ffff:192.168.0.5".parse().unwrap()));
+    assert!(!access_list.is_allowed("::ffff:192.168.1.5".parse().unwrap()));
+
+    assert!(AccessList::parse(&["not an ip"], &[]).is_err());
+}
@@ -0,0 +1,397 @@
+use crate::{
+    capabilities::Capabilities, client::Introduction, ClientToServerMessage, CustomMessage,
+    EtermFrame, OpenUrlPolicy, OutgoingInput, ServerToClientMessage, SessionInfo,
+};
+use anyhow::Context as _;
+use egui::output::OpenUrl;
+use parking_lot::Mutex;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// Something that happened on the connection to the server.
+pub enum ClientEvent {
+    /// We connected (or reconnected) to the server.
+    Connected,
+
+    /// We lost the connection. We will keep trying to reconnect.
+    Disconnected,
+
+    /// What to paint.
+    ///
+    /// Every frame is delivered, so texture updates must be applied in order.
+    Frame(EtermFrame),
+
+    /// What the server told us about our session and its spectators.
+    SessionInfo(SessionInfo),
+
+    /// An application-defined message from the server (see [`crate::Server::send_custom`]).
+    ///
+    /// Decode it with [`CustomMessage::decode`].
+    Custom(CustomMessage),
+
+    /// The server wants us to open this url, and our [`OpenUrlPolicy::Ask`]
+    /// says to ask the user. If they agree, open it yourself.
+    OpenUrl(OpenUrl),
+}
+
+/// What [`Client`] asks the connection task to send.
+enum Outgoing {
+    Input(OutgoingInput),
+    Message(ClientToServerMessage),
+}
+
+/// Like [`crate::Client`], but as a [`futures_core::Stream`] of [`ClientEvent`]s.
+///
+/// The connection is run as a [`tokio`] task, which is stopped when the [`Client`] is dropped.
+pub struct Client {
+    addr: String,
+    event_rx: mpsc::UnboundedReceiver<ClientEvent>,
+    outgoing_tx: mpsc::UnboundedSender<Outgoing>,
+    connected: Arc<AtomicBool>,
+    introduction: Arc<Mutex<Introduction>>,
+    open_url_policy: Arc<Mutex<OpenUrlPolicy>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Client {
+    /// Connects to the given eterm server, reconnecting whenever the connection is lost.
+    ///
+    /// Must be called from within a [`tokio`] runtime.
+    pub fn new(addr: String) -> Self {
+        let mut capabilities = Capabilities::new("eterm", env!("CARGO_PKG_VERSION"));
+        // We can't do these (yet), see the module docs:
+        capabilities
+            .features
+            .retain(|feature| feature != "spectate" && feature != "transfer");
+        Self::with_capabilities(addr, capabilities)
+    }
+
+    /// Like [`Self::new`], but say hello with these [`Capabilities`] from the start.
+    ///
+    /// Must be called from within a [`tokio`] runtime.
+    pub fn with_capabilities(addr: String, capabilities: Capabilities) -> Self {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let connected = Arc::new(AtomicBool::new(false));
+        let introduction = Arc::new(Mutex::new(Introduction::new(capabilities)));
+        let open_url_policy: Arc<Mutex<OpenUrlPolicy>> = Default::default();
+
+        let task = tokio::spawn(run(
+            addr.clone(),
+            event_tx,
+            outgoing_rx,
+            connected.clone(),
+            introduction.clone(),
+            open_url_policy.clone(),
+        ));
+
+        Self {
+            addr,
+            event_rx,
+            outgoing_tx,
+            connected,
+            introduction,
+            open_url_policy,
+            task,
+        }
+    }
+
+    /// The address we are connected to or trying to connect to.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Which urls the server may make us open (see [`EtermFrame::platform_output`]).
+    ///
+    /// The default is [`OpenUrlPolicy::Block`].
+    /// Urls that are not allowed are removed from the frames before we deliver them.
+    pub fn set_open_url_policy(&self, policy: OpenUrlPolicy) {
+        *self.open_url_policy.lock() = policy;
+    }
+
+    /// Tell the server who we are and what we can do, see [`Capabilities`].
+    ///
+    /// This is remembered across reconnects.
+    /// If you know them up front, use [`Self::with_capabilities`] instead.
+    pub fn set_capabilities(&self, capabilities: Capabilities) {
+        self.introduce(|introduction| {
+            introduction.capabilities = capabilities.clone();
+            ClientToServerMessage::Hello(capabilities)
+        });
+    }
+
+    /// See [`Self::set_capabilities`].
+    pub fn capabilities(&self) -> Capabilities {
+        self.introduction.lock().capabilities.clone()
+    }
+
+    /// What to call us when the server shows our pointer to others.
+    ///
+    /// This is remembered across reconnects.
+    pub fn set_name(&self, name: impl Into<String>) {
+        let name = name.into();
+        self.introduce(|introduction| {
+            introduction.name = Some(name.clone());
+            ClientToServerMessage::SetName { name }
+        });
+    }
+
+    /// Send a value on an application-defined channel to the server,
+    /// which receives it with [`crate::Server::on_custom`].
+    ///
+    /// Like input, this is queued while we are disconnected.
+    ///
+    /// # Errors
+    /// If the value can't be encoded,
+    /// or is larger than [`crate::MAX_CLIENT_MESSAGE_SIZE`].
+    pub fn send_custom<T: serde::Serialize + ?Sized>(
+        &self,
+        channel: impl Into<String>,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let message = CustomMessage::new(channel, value)?;
+        anyhow::ensure!(
+            message.payload.len() as u64 <= crate::MAX_CLIENT_MESSAGE_SIZE,
+            "custom message is too large: {:.1} MB",
+            message.payload.len() as f32 * 1e-6
+        );
+        self.outgoing_tx
+            .send(Outgoing::Message(ClientToServerMessage::Custom(message)))
+            .ok();
+        Ok(())
+    }
+
+    /// Change what we tell the server on connect.
+    /// If we are connected, also tell it now with the returned message.
+    fn introduce(&self, change: impl FnOnce(&mut Introduction) -> ClientToServerMessage) {
+        let mut introduction = self.introduction.lock();
+        let message = change(&mut introduction);
+        if self.connected.load(SeqCst) {
+            self.outgoing_tx.send(Outgoing::Message(message)).ok();
+        }
+    }
+
+    /// Wait for the next event.
+    ///
+    /// Same as `StreamExt::next`.
+    pub async fn recv(&mut self) -> Option<ClientEvent> {
+        self.event_rx.recv().await
+    }
+
+    /// Where to send input to the server.
+    pub fn input_sink(&self) -> InputSink {
+        InputSink {
+            outgoing_tx: self.outgoing_tx.clone(),
+        }
+    }
+}
+
+impl futures_core::Stream for Client {
+    type Item = ClientEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ClientEvent>> {
+        self.get_mut().event_rx.poll_recv(cx)
+    }
+}
+
+/// A [`futures_sink::Sink`] of [`egui::RawInput`] to send to the server.
+///
+/// Input is queued, so the sink is always ready.
+/// Input sent while disconnected is sent once we reconnect.
+/// Only new events and input state changes are sent over the network.
+#[derive(Clone)]
+pub struct InputSink {
+    outgoing_tx: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl futures_sink::Sink<egui::RawInput> for InputSink {
+    type Error = anyhow::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, raw_input: egui::RawInput) -> anyhow::Result<()> {
+        self.outgoing_tx
+            .send(Outgoing::Input(OutgoingInput {
+                raw_input,
+                client_time: now(),
+            }))
+            .map_err(|_err| anyhow::anyhow!("eterm client has been dropped"))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn run(
+    addr: String,
+    event_tx: mpsc::UnboundedSender<ClientEvent>,
+    mut outgoing_rx: mpsc::UnboundedReceiver<Outgoing>,
+    connected: Arc<AtomicBool>,
+    introduction: Arc<Mutex<Introduction>>,
+    open_url_policy: Arc<Mutex<OpenUrlPolicy>>,
+) {
+    tracing::info!("Connecting to {}…", addr);
+    loop {
+        match tokio::net::TcpStream::connect(&addr).await {
+            Ok(tcp_stream) => {
+                tracing::info!("Connected!");
+                let introduction_messages = {
+                    // Under the lock, so that changes are either in here or queued:
+                    let introduction = introduction.lock();
+                    connected.store(true, SeqCst);
+                    introduction.messages()
+                };
+                if event_tx.send(ClientEvent::Connected).is_err() {
+                    return;
+                }
+                let result = run_connection(
+                    tcp_stream,
+                    introduction_messages,
+                    &event_tx,
+                    &mut outgoing_rx,
+                    &open_url_policy,
+                )
+                .await;
+                {
+                    let _introduction = introduction.lock();
+                    connected.store(false, SeqCst);
+                }
+                if let Err(err) = result {
+                    tracing::info!(
+                        "Connection lost: {}",
+                        crate::error_display_chain(err.as_ref())
+                    );
+                } else {
+                    tracing::info!("Connection closed.",);
+                }
+                if event_tx.send(ClientEvent::Disconnected).is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                tracing::debug!("Failed to connect to {}: {}", addr, err);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn run_connection(
+    tcp_stream: tokio::net::TcpStream,
+    introduction: Vec<ClientToServerMessage>,
+    event_tx: &mpsc::UnboundedSender<ClientEvent>,
+    outgoing_rx: &mut mpsc::UnboundedReceiver<Outgoing>,
+    open_url_policy: &Mutex<OpenUrlPolicy>,
+) -> anyhow::Result<()> {
+    let (mut read_half, mut write_half) = tcp_stream.into_split();
+
+    let receive = async {
+        let mut buffer = vec![];
+        while super::read_packet(&mut read_half, &mut buffer)
+            .await
+            .context("receive")?
+        {
+            let message = crate::decode_message(&buffer).context("decode")?;
+            match message {
+                ServerToClientMessage::Frame {
+                    frame_index,
+                    mut platform_output,
+                    clipped_net_mesh,
+                    client_time: _,
+                    textures_delta,
+                } => {
+                    let url_request = open_url_policy.lock().apply(&mut platform_output);
+                    let frame = EtermFrame {
+                        frame_index,
+                        platform_output,
+                        clipped_net_mesh,
+                        textures_delta,
+                    };
+                    if event_tx.send(ClientEvent::Frame(frame)).is_err() {
+                        break; // nobody is listening
+                    }
+                    if let Some(request) = url_request {
+                        if event_tx.send(ClientEvent::OpenUrl(request)).is_err() {
+                            break; // nobody is listening
+                        }
+                    }
+                }
+                ServerToClientMessage::Custom(message) => {
+                    if event_tx.send(ClientEvent::Custom(message)).is_err() {
+                        break; // nobody is listening
+                    }
+                }
+                ServerToClientMessage::SessionInfo(session_info) => {
+                    if event_tx
+                        .send(ClientEvent::SessionInfo(session_info))
+                        .is_err()
+                    {
+                        break; // nobody is listening
+                    }
+                }
+                ServerToClientMessage::Transfer(message) => {
+                    if let crate::transfer::TransferMessage::Start { name, .. } = message {
+                        tracing::warn!(
+                            "Ignoring download of {:?}: file transfers are not supported by the async client",
+                            name
+                        );
+                    }
+                }
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    let send = async {
+        // What the server knows about our input state:
+        let mut input_state = egui::RawInput::default();
+
+        for message in introduction {
+            super::write_message(&mut write_half, &message)
+                .await
+                .context("send")?;
+        }
+
+        while let Some(outgoing) = outgoing_rx.recv().await {
+            let message = match outgoing {
+                Outgoing::Input(input) => input.encode(&mut input_state),
+                Outgoing::Message(message) => Some(message),
+            };
+            if let Some(message) = message {
+                super::write_message(&mut write_half, &message)
+                    .await
+                    .context("send")?;
+            }
+        }
+        super::write_message(&mut write_half, &ClientToServerMessage::Goodbye).await
+    };
+
+    tokio::select! {
+        result = receive => result,
+        result = send => result,
+    }
+}
+
+fn now() -> f64 {
+    std::time::UNIX_EPOCH.elapsed().unwrap().as_secs_f64()
+}
@@ -0,0 +1,285 @@
+//! Async versions of [`crate::Client`] and [`crate::Server`], built on [`tokio`].
+//!
+//! Enabled with the `tokio` feature.
+//!
+//! The async [`Server`] runs each connection as its own task, and the async [`Client`]
+//! is a [`futures_core::Stream`] of [`ClientEvent`]s, with input sent through an [`InputSink`].
+//! Nothing in here sleeps in a polling loop: everything is driven by socket readiness and timers.
+//!
+//! Custom messages go both ways: see [`Client::send_custom`], [`ClientEvent::Custom`],
+//! [`Server::on_custom`] and [`Outbox`]. The async [`Client`] says hello with its
+//! [`crate::Capabilities`] and name like the blocking one.
+//!
+//! Not supported yet, compared to the blocking versions:
+//! * File transfers. The async [`Client`] refuses downloads and has no uploads,
+//!   and the async [`Server`] has no `send_file`.
+//! * Clipboard sync on the [`Client`]: copied text is left in the frame's platform output.
+//! * Spectating, hand-off, shared sessions and presence. The async [`Server`] refuses
+//!   spectate requests with a notification.
+
+mod client;
+mod server;
+
+pub use client::{Client, ClientEvent, InputSink};
+pub use server::{Notifier, Outbox, Server};
+
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+/// Read the next packet into `buffer`.
+///
+/// Returns `false` if the other side closed the connection between packets.
+async fn read_packet(
+    reader: &mut (impl tokio::io::AsyncRead + Unpin),
+    buffer: &mut Vec<u8>,
+) -> anyhow::Result<bool> {
+    let mut header = [0_u8; crate::PACKET_HEADER_LEN];
+
+    // Distinguish a clean close from one in the middle of a packet:
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Ok(false);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+
+    let length = crate::parse_packet_header(&header)?;
+    buffer.resize(length, 0);
+    reader.read_exact(buffer).await?;
+    Ok(true)
+}
+
+async fn write_packet(
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    let length = (packet.len() as u32).to_le_bytes();
+    writer.write_all(&crate::PROTOCOL_HEADER).await?;
+    writer.write_all(&length).await?;
+    writer.write_all(packet).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn write_message<M: crate::Message>(
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    message: &M,
+) -> anyhow::Result<()> {
+    write_packet(writer, &crate::encode_message(message)?).await
+}
+
+#[tokio::test]
+async fn test_async_client_server() {
+    use futures_sink::Sink as _;
+    use std::pin::Pin;
+
+    let server = Server::new("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run(|egui_ctx, _client_id| {
+        egui::CentralPanel::default().show(egui_ctx, |ui| {
+            ui.label("Hello async world!");
+        });
+    }));
+
+    let mut client = Client::new(addr.to_string());
+    assert!(matches!(client.recv().await, Some(ClientEvent::Connected)));
+    assert!(matches!(
+        client.recv().await,
+        Some(ClientEvent::SessionInfo(_))
+    ));
+
+    let mut input_sink = client.input_sink();
+    Pin::new(&mut input_sink)
+        .start_send(egui::RawInput::default())
+        .unwrap();
+
+    match client.recv().await {
+        Some(ClientEvent::Frame(frame)) => {
+            assert!(!frame.clipped_net_mesh.is_empty());
+        }
+        _ => panic!("Expected a frame"),
+    }
+}
+
+#[tokio::test]
+async fn test_async_notify() {
+    use futures_sink::Sink as _;
+    use std::{pin::Pin, time::Duration};
+
+    let mut server = Server::new("127.0.0.1:0").await.unwrap();
+    server.set_minimum_update_interval(Duration::from_secs(3600));
+    let addr = server.local_addr().unwrap();
+    let notifier = server.notifier();
+    tokio::spawn(server.run(|egui_ctx, _client_id| {
+        egui::CentralPanel::default().show(egui_ctx, |ui| {
+            ui.label("Hello async world!");
+        });
+    }));
+
+    let mut client = Client::new(addr.to_string());
+    let mut input_sink = client.input_sink();
+    Pin::new(&mut input_sink)
+        .start_send(egui::RawInput {
+            screen_rect: Some(egui::Rect::from_min_size(
+                egui::Pos2::ZERO,
+                egui::vec2(800.0, 600.0),
+            )),
+            ..Default::default()
+        })
+        .unwrap();
+
+    // Wait for the ui to settle:
+    let mut frames = 0;
+    let start = std::time::Instant::now();
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(10), "Timeout");
+        match tokio::time::timeout(Duration::from_millis(500), client.recv()).await {
+            Ok(Some(ClientEvent::Frame(_))) => frames += 1,
+            Ok(None) => panic!("Client closed"),
+            Err(_) if frames > 0 => break,
+            Ok(Some(_)) | Err(_) => {}
+        }
+    }
+
+    // A notification needs a new frame:
+    notifier.notify(
+        crate::notifications::Target::All,
+        crate::Notification::info("Deploy starting"),
+    );
+    loop {
+        match tokio::time::timeout(Duration::from_secs(10), client.recv()).await {
+            Ok(Some(ClientEvent::Frame(_))) => break,
+            Ok(Some(_)) => {}
+            Ok(None) => panic!("Client closed"),
+            Err(_) => panic!("Timeout"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_async_client_blocks_urls() {
+    use futures_sink::Sink as _;
+    use std::pin::Pin;
+
+    let server = Server::new("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run(|egui_ctx, _client_id| {
+        egui_ctx.output().open_url("https://evil.example.com");
+    }));
+
+    let mut client = Client::new(addr.to_string());
+    let next_frame = |client: &mut Client| {
+        Pin::new(&mut client.input_sink())
+            .start_send(egui::RawInput::default())
+            .unwrap();
+    };
+
+    next_frame(&mut client);
+    loop {
+        if let Some(ClientEvent::Frame(frame)) = client.recv().await {
+            assert!(frame.platform_output.open_url.is_none());
+            break;
+        }
+    }
+
+    client.set_open_url_policy(crate::OpenUrlPolicy::Ask(Default::default()));
+    next_frame(&mut client);
+    loop {
+        match client.recv().await {
+            Some(ClientEvent::Frame(frame)) => assert!(frame.platform_output.open_url.is_none()),
+            Some(ClientEvent::OpenUrl(request)) => {
+                assert_eq!(request.url, "https://evil.example.com");
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_async_custom_and_hello() {
+    use parking_lot::Mutex;
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::mpsc;
+
+    let mut server = Server::new("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let outbox = server.outbox();
+    let (greeting_tx, mut greeting_rx) = mpsc::unbounded_channel();
+    server.on_custom("greeting", move |client_id, greeting: String| {
+        greeting_tx.send((client_id, greeting)).ok();
+    });
+    let viewer_names = Arc::new(Mutex::new(vec![]));
+    tokio::spawn(server.run({
+        let viewer_names = viewer_names.clone();
+        move |egui_ctx, _client_id| {
+            viewer_names
+                .lock()
+                .push(crate::Capabilities::of(egui_ctx).viewer_name);
+        }
+    }));
+
+    let capabilities = crate::Capabilities::new("async_viewer", "1.0.0");
+    let mut client = Client::with_capabilities(addr.to_string(), capabilities);
+    client.send_custom("greeting", "hello").unwrap();
+
+    let (client_id, greeting) = tokio::time::timeout(Duration::from_secs(10), greeting_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(greeting, "hello");
+
+    // The ui sees our hello:
+    let start = std::time::Instant::now();
+    while !viewer_names
+        .lock()
+        .iter()
+        .any(|name| name == "async_viewer")
+    {
+        assert!(start.elapsed() < Duration::from_secs(10), "Timeout");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    outbox.send_custom(client_id, "reply", "hi back").unwrap();
+    loop {
+        match tokio::time::timeout(Duration::from_secs(10), client.recv()).await {
+            Ok(Some(ClientEvent::Custom(message))) => {
+                assert_eq!(message.channel, "reply");
+                assert_eq!(message.decode::<String>().unwrap(), "hi back");
+                break;
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => panic!("Client closed"),
+            Err(_) => panic!("Timeout"),
+        }
+    }
+
+    assert!(outbox
+        .send_custom(crate::ClientId(1234), "reply", "nobody")
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_async_access_list_disconnects_idle_client() {
+    use std::time::Duration;
+
+    let mut server = Server::new("127.0.0.1:0").await.unwrap();
+    server.set_minimum_update_interval(Duration::from_secs(3600));
+    let addr = server.local_addr().unwrap();
+    let access_list = server.access_list();
+    tokio::spawn(server.run(|_egui_ctx, _client_id| {}));
+
+    let mut client = Client::new(addr.to_string());
+    assert!(matches!(client.recv().await, Some(ClientEvent::Connected)));
+    assert!(matches!(
+        client.recv().await,
+        Some(ClientEvent::SessionInfo(_))
+    ));
+
+    access_list.set(crate::AccessList::parse(&[], &["127.0.0.0/8"]).unwrap());
+    loop {
+        match tokio::time::timeout(Duration::from_secs(10), client.recv()).await {
+            Ok(Some(ClientEvent::Disconnected)) => break,
+            Ok(Some(_)) => {}
+            Ok(None) => panic!("Client closed"),
+            Err(_) => panic!("Timeout"),
+        }
+    }
+}
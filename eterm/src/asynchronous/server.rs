@@ -0,0 +1,481 @@
+use crate::{
+    audit::SessionAudit,
+    custom::CustomHandlers,
+    notifications::{Notification, Target},
+    rate_limit::{RateLimitVerdict, RateLimiter},
+    server::{ControlFlow, RoleFn, Session},
+    AuditLog, ClientId, ClientToServerMessage, CustomMessage, InputLimits, InputMode, RateLimit,
+    Role, ServerToClientMessage, SharedAccessList, DEFAULT_MIN_UPDATE_INTERVAL,
+};
+use anyhow::Context as _;
+use parking_lot::Mutex;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc},
+};
+
+type DoUi = dyn Fn(&egui::Context, ClientId) + Send + Sync;
+
+/// Notifications sent faster than a client task handles them are dropped after this many.
+const NOTIFICATION_QUEUE_LEN: usize = 64;
+
+/// Shows notifications to the clients of an async [`Server`], see [`Server::notifier`].
+#[derive(Clone)]
+pub struct Notifier {
+    tx: broadcast::Sender<(Target, Notification)>,
+}
+
+impl Notifier {
+    /// Show a notification on top of the ui of one or all connected clients.
+    pub fn notify(&self, target: Target, notification: Notification) {
+        self.tx.send((target, notification)).ok(); // fails if no one is connected
+    }
+}
+
+/// Sends application-defined messages to the clients of an async [`Server`],
+/// see [`Server::outbox`].
+///
+/// Messages to clients that don't support the `"custom"` [feature](crate::capabilities::FEATURES)
+/// are dropped.
+#[derive(Clone, Default)]
+pub struct Outbox {
+    clients: Arc<Mutex<HashMap<ClientId, mpsc::UnboundedSender<CustomMessage>>>>,
+}
+
+impl Outbox {
+    /// Send a value on an application-defined channel to one client.
+    ///
+    /// # Errors
+    /// If the value can't be encoded, or the client is not connected.
+    pub fn send_custom<T: serde::Serialize + ?Sized>(
+        &self,
+        client_id: ClientId,
+        channel: impl Into<String>,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let message = CustomMessage::new(channel, value)?;
+        self.clients
+            .lock()
+            .get(&client_id)
+            .and_then(|tx| tx.send(message).ok())
+            .with_context(|| format!("Client {} is not connected", client_id))
+    }
+
+    /// Send a value on an application-defined channel to all connected clients.
+    ///
+    /// # Errors
+    /// If the value can't be encoded.
+    pub fn broadcast_custom<T: serde::Serialize + ?Sized>(
+        &self,
+        channel: impl Into<String>,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let message = CustomMessage::new(channel, value)?;
+        for tx in self.clients.lock().values() {
+            tx.send(message.clone()).ok();
+        }
+        Ok(())
+    }
+}
+
+/// Like [`crate::Server`], but each client connection runs as its own [`tokio`] task.
+///
+/// Spectating and handing off sessions is not supported: such requests are refused
+/// with a notification to the client.
+pub struct Server {
+    next_client_id: u64,
+    tcp_listener: TcpListener,
+    minimum_update_interval: Duration,
+    input_mode: InputMode,
+    input_limits: InputLimits,
+    rate_limit: Option<RateLimit>,
+    access_list: SharedAccessList,
+    audit_log: Option<Arc<Mutex<AuditLog>>>,
+    role_fn: Arc<RoleFn>,
+    custom_handlers: Arc<Mutex<CustomHandlers<ClientId>>>,
+    notifier: Notifier,
+    outbox: Outbox,
+}
+
+impl Server {
+    /// Start listening for connections on this addr (e.g. "0.0.0.0:8585")
+    ///
+    /// # Errors
+    /// Can fail if the port is already taken.
+    pub async fn new(bind_addr: &str) -> anyhow::Result<Self> {
+        let tcp_listener = TcpListener::bind(bind_addr)
+            .await
+            .context("binding server TCP socket")?;
+
+        Ok(Self {
+            next_client_id: 0,
+            tcp_listener,
+            minimum_update_interval: DEFAULT_MIN_UPDATE_INTERVAL,
+            input_mode: Default::default(),
+            input_limits: Default::default(),
+            rate_limit: None,
+            access_list: Default::default(),
+            audit_log: None,
+            role_fn: Arc::new(|_| Role::Operator),
+            custom_handlers: Default::default(),
+            notifier: Notifier {
+                tx: broadcast::channel(NOTIFICATION_QUEUE_LEN).0,
+            },
+            outbox: Default::default(),
+        })
+    }
+
+    /// The address we are listening on.
+    ///
+    /// # Errors
+    /// Underlying TCP errors.
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.tcp_listener.local_addr()?)
+    }
+
+    /// Send a new frame to each client at least this often.
+    /// Default: one second.
+    pub fn set_minimum_update_interval(&mut self, minimum_update_interval: Duration) {
+        self.minimum_update_interval = minimum_update_interval;
+    }
+
+    /// How to feed client input to egui.
+    /// Default: [`InputMode::Merged`].
+    pub fn set_input_mode(&mut self, input_mode: InputMode) {
+        self.input_mode = input_mode;
+    }
+
+    /// Limits on the (untrusted) input we accept from clients.
+    pub fn set_input_limits(&mut self, input_limits: InputLimits) {
+        self.input_limits = input_limits;
+    }
+
+    /// Limit how much each client may send us, or `None` for no limit.
+    ///
+    /// Default: no limit. [`RateLimit::default`] is a good start.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limit = rate_limit;
+    }
+
+    /// Which addresses may connect. Everyone by default.
+    ///
+    /// Use the returned handle to update the lists at runtime, also after calling [`Self::run`].
+    /// Connected clients that the new lists deny are disconnected.
+    pub fn access_list(&self) -> SharedAccessList {
+        self.access_list.clone()
+    }
+
+    /// Use the returned handle to show notifications to clients,
+    /// also after calling [`Self::run`].
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    /// Use the returned handle to send custom messages to clients,
+    /// also after calling [`Self::run`].
+    pub fn outbox(&self) -> Outbox {
+        self.outbox.clone()
+    }
+
+    /// Record connections and what each client clicks and types.
+    pub fn set_audit_log(&mut self, audit_log: Option<AuditLog>) {
+        self.audit_log = audit_log.map(|audit_log| Arc::new(Mutex::new(audit_log)));
+    }
+
+    /// Decide the [`Role`] of each new connection based on its address.
+    ///
+    /// By default everyone is a [`Role::Operator`].
+    pub fn set_role_fn(&mut self, role_fn: impl Fn(SocketAddr) -> Role + Send + Sync + 'static) {
+        self.role_fn = Arc::new(role_fn);
+    }
+
+    /// Handle the [`crate::CustomMessage`]s clients send on this channel.
+    ///
+    /// The handler is called from the client tasks, one message at a time.
+    /// Messages from [`Role::Viewer`]s are dropped.
+    pub fn on_custom<T: serde::de::DeserializeOwned>(
+        &mut self,
+        channel: impl Into<String>,
+        handler: impl FnMut(ClientId, T) + Send + 'static,
+    ) {
+        self.custom_handlers.lock().insert(channel.into(), handler);
+    }
+
+    /// Accept clients forever, spawning a task for each one.
+    ///
+    /// `do_ui` is called from the client tasks whenever a client needs a new frame,
+    /// so any state it shares must be behind a lock.
+    ///
+    /// # Errors
+    /// Underlying TCP errors when accepting new clients.
+    pub async fn run(
+        mut self,
+        do_ui: impl Fn(&egui::Context, ClientId) + Send + Sync + 'static,
+    ) -> anyhow::Result<()> {
+        let do_ui: Arc<DoUi> = Arc::new(do_ui);
+
+        loop {
+            let (tcp_stream, client_addr) = self
+                .tcp_listener
+                .accept()
+                .await
+                .context("eterm server TCP error")?;
+
+            if !self.access_list.is_allowed(client_addr.ip()) {
+                tracing::warn!(
+                    "Rejected connection from {}: not allowed by the access list",
+                    client_addr
+                );
+                continue; // drops and closes the connection
+            }
+
+            let client_id = ClientId(self.next_client_id);
+            self.next_client_id += 1;
+
+            let mut session = Session::new(client_id);
+            session.role = (self.role_fn)(client_addr);
+
+            tracing::info!(
+                "Client {} ({}) connected as {:?}",
+                client_id.0,
+                client_addr,
+                session.role
+            );
+
+            session.input_mode = self.input_mode;
+            session.input_limits = self.input_limits.clone();
+            session.audit = self
+                .audit_log
+                .clone()
+                .map(|audit_log| SessionAudit::new(audit_log, client_id.0, client_addr));
+            if let Some(audit) = &mut session.audit {
+                audit.connected();
+            }
+            let do_ui = do_ui.clone();
+            let access_list = self.access_list.clone();
+            let minimum_update_interval = self.minimum_update_interval;
+            let custom_handlers = self.custom_handlers.clone();
+            let notification_rx = self.notifier.tx.subscribe();
+            let (custom_tx, custom_rx) = mpsc::unbounded_channel();
+            self.outbox.clients.lock().insert(client_id, custom_tx);
+            let outbox = self.outbox.clone();
+            let rate_limiter = self.rate_limit.clone().map(|rate_limit| {
+                RateLimiter::new(
+                    rate_limit,
+                    format!("Client {} ({})", client_id.0, client_addr),
+                )
+            });
+
+            tokio::spawn(async move {
+                let result = serve_client(
+                    tcp_stream,
+                    client_addr,
+                    &access_list,
+                    &mut session,
+                    rate_limiter,
+                    &*do_ui,
+                    minimum_update_interval,
+                    &custom_handlers,
+                    notification_rx,
+                    custom_rx,
+                )
+                .await;
+                outbox.clients.lock().remove(&client_id);
+
+                if let Some(mut audit) = session.audit.take() {
+                    audit.disconnected();
+                }
+
+                match result {
+                    Ok(()) => {
+                        tracing::info!("Client {} ({}) disconnected", client_id.0, client_addr);
+                    }
+                    Err(err) => {
+                        tracing::error!(
+                            "Client {} ({}): {}. Disconnecting.",
+                            client_id.0,
+                            client_addr,
+                            crate::error_display_chain(err.as_ref())
+                        );
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// How often we check if a connected client is still allowed by the access list.
+const ACCESS_LIST_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_client(
+    tcp_stream: tokio::net::TcpStream,
+    client_addr: SocketAddr,
+    access_list: &SharedAccessList,
+    session: &mut Session,
+    mut rate_limiter: Option<RateLimiter>,
+    do_ui: &DoUi,
+    minimum_update_interval: Duration,
+    custom_handlers: &Mutex<CustomHandlers<ClientId>>,
+    mut notification_rx: broadcast::Receiver<(Target, Notification)>,
+    mut custom_rx: mpsc::UnboundedReceiver<CustomMessage>,
+) -> anyhow::Result<()> {
+    let (mut read_half, mut write_half) = tcp_stream.into_split();
+
+    // Reading a packet is not cancel-safe, so we do it in its own future
+    // that we keep polling until the connection closes:
+    let (message_tx, mut message_rx) = mpsc::unbounded_channel::<ClientToServerMessage>();
+    let receive = async move {
+        let mut buffer = vec![];
+        loop {
+            if let Some(rate_limiter) = &mut rate_limiter {
+                match rate_limiter.check(std::time::Instant::now()) {
+                    RateLimitVerdict::Allow => {}
+                    RateLimitVerdict::Throttle(wait) => {
+                        tokio::time::sleep(wait).await;
+                        continue;
+                    }
+                    RateLimitVerdict::Disconnect => {
+                        anyhow::bail!("Rate limit exceeded");
+                    }
+                }
+            }
+
+            if !super::read_packet(&mut read_half, &mut buffer)
+                .await
+                .context("receive")?
+            {
+                break;
+            }
+            let message = crate::decode_message(&buffer).context("decode")?;
+            if let Some(rate_limiter) = &mut rate_limiter {
+                rate_limiter.consume_message(buffer.len(), &message);
+            }
+            if message_tx.send(message).is_err() {
+                break;
+            }
+        }
+        anyhow::Ok(())
+    };
+    tokio::pin!(receive);
+    let mut receive_done = false;
+    let mut notifier_closed = false;
+
+    // An idle client may not wake us for a long time,
+    // so we also check the access list at regular intervals:
+    let mut access_check = tokio::time::interval(ACCESS_LIST_CHECK_INTERVAL);
+
+    let session_info = crate::SessionInfo {
+        client_id: session.client_id,
+        spectating: None,
+        spectators: vec![],
+    };
+    super::write_message(
+        &mut write_half,
+        &ServerToClientMessage::SessionInfo(session_info.clone()),
+    )
+    .await
+    .context("send")?;
+
+    loop {
+        if !access_list.is_allowed(client_addr.ip()) {
+            tracing::warn!(
+                "Client {} ({}) is no longer allowed by the access list",
+                session.client_id.0,
+                client_addr
+            );
+            return Ok(());
+        }
+
+        let next_frame_time = session.next_frame_time(minimum_update_interval);
+
+        tokio::select! {
+            biased;
+
+            message = message_rx.recv() => match message {
+                Some(message) => {
+                    match session.on_message(message) {
+                        ControlFlow::Continue => {}
+                        ControlFlow::Disconnect => return Ok(()),
+                        ControlFlow::Custom(message) => {
+                            custom_handlers.lock().handle(session.client_id, &message);
+                        }
+                        ControlFlow::Spectate(_) | ControlFlow::HandOff(_) => {
+                            let reason = "Spectating is not supported by this server";
+                            tracing::warn!("Client {}: {}", session.client_id.0, reason);
+                            session.notifications.push(
+                                crate::Notification::warning(reason)
+                                    .with_duration(Duration::from_secs(10)),
+                            );
+                            session.request_repaint();
+                            super::write_message(
+                                &mut write_half,
+                                &ServerToClientMessage::SessionInfo(session_info.clone()),
+                            )
+                            .await
+                            .context("send")?;
+                        }
+                    }
+
+                    // Replies about uploads:
+                    while let Some(message) = session.transfers.next_message() {
+                        super::write_message(
+                            &mut write_half,
+                            &ServerToClientMessage::Transfer(message),
+                        )
+                        .await
+                        .context("send")?;
+                    }
+                }
+                None => return Ok(()), // connection closed
+            },
+
+            result = &mut receive, if !receive_done => {
+                result?;
+                receive_done = true; // handle the remaining messages before we quit
+            }
+
+            Some(message) = custom_rx.recv() => {
+                if session.capabilities.has_feature("custom") {
+                    super::write_message(&mut write_half, &ServerToClientMessage::Custom(message))
+                        .await
+                        .context("send")?;
+                } else {
+                    tracing::debug!(
+                        "Client {} doesn't support custom messages; dropped one on channel {:?}",
+                        session.client_id.0,
+                        message.channel
+                    );
+                }
+            }
+
+            notification = notification_rx.recv(), if !notifier_closed => match notification {
+                Ok((target, notification)) => {
+                    if target.includes(session.client_id) {
+                        session.notifications.push(notification);
+                        session.request_repaint();
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    tracing::warn!(
+                        "Client {}: dropped {} notifications",
+                        session.client_id.0,
+                        count
+                    );
+                }
+                Err(broadcast::error::RecvError::Closed) => notifier_closed = true,
+            },
+
+            _ = access_check.tick() => {}
+
+            () = tokio::time::sleep_until(next_frame_time.into()) => {
+                let frame = session.create_frame(&mut |egui_ctx, client_id| do_ui(egui_ctx, client_id));
+                let message = session.frame_message(frame);
+                super::write_message(&mut write_half, &message)
+                    .await
+                    .context("send")?;
+            }
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Audit log of what remote operators did.
+
+use parking_lot::Mutex;
+use std::{io::Write as _, net::SocketAddr, sync::Arc};
+
+/// A widget an operator interacted with, as reported by egui in [`egui::PlatformOutput`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct AuditWidget {
+    pub typ: egui::WidgetType,
+    pub label: Option<String>,
+    pub value: Option<f64>,
+    pub selected: Option<bool>,
+
+    /// The contents of a text field, if the log records text (see [`AuditLog::with_text`]).
+    pub text: Option<String>,
+}
+
+impl From<&egui::WidgetInfo> for AuditWidget {
+    fn from(info: &egui::WidgetInfo) -> Self {
+        Self {
+            typ: info.typ,
+            label: info.label.clone(),
+            value: info.value,
+            selected: info.selected,
+            text: info.current_text_value.clone(),
+        }
+    }
+}
+
+impl AuditWidget {
+    fn is_same_widget(&self, other: &Self) -> bool {
+        self.typ == other.typ && self.label == other.label
+    }
+}
+
+/// Something that happened in a client session.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "type")]
+pub enum AuditEvent {
+    Connected,
+    Disconnected,
+    Clicked {
+        widget: AuditWidget,
+    },
+    DoubleClicked {
+        widget: AuditWidget,
+    },
+    TripleClicked {
+        widget: AuditWidget,
+    },
+
+    /// The final value of a widget (slider, checkbox, …) after the operator changed it.
+    ValueChanged {
+        widget: AuditWidget,
+    },
+
+    /// Text entered into a text field.
+    ///
+    /// Recorded when the operator is done editing (presses enter or tab,
+    /// interacts with another widget, or disconnects).
+    TextCommitted {
+        widget: AuditWidget,
+
+        /// Only if the log records text (see [`AuditLog::with_text`]).
+        text: Option<String>,
+
+        /// Number of characters in the text.
+        len: usize,
+    },
+}
+
+/// One entry in the [`AuditLog`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct AuditRecord {
+    /// Seconds since epoch.
+    pub time: f64,
+    pub client_id: u64,
+    pub addr: SocketAddr,
+    pub event: AuditEvent,
+}
+
+enum AuditSink {
+    Tracing,
+    JsonLines(std::io::BufWriter<std::fs::File>),
+    Callback(Box<dyn FnMut(&AuditRecord) + Send>),
+}
+
+/// Records connections, and what each operator clicked and typed.
+///
+/// Set it with `Server::set_audit_log`.
+///
+/// By default only the length of the text typed into text fields is recorded.
+/// Use [`Self::with_text`] to also record the text itself.
+pub struct AuditLog {
+    sink: AuditSink,
+    record_text: bool,
+}
+
+impl AuditLog {
+    /// Log to [`tracing`], with the target `eterm::audit`.
+    pub fn tracing() -> Self {
+        Self {
+            sink: AuditSink::Tracing,
+            record_text: false,
+        }
+    }
+
+    /// Append one JSON object per line to the given file.
+    ///
+    /// # Errors
+    /// If the file can't be opened.
+    pub fn json_lines(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+        use anyhow::Context as _;
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening audit log {:?}", path))?;
+        Ok(Self {
+            sink: AuditSink::JsonLines(std::io::BufWriter::new(file)),
+            record_text: false,
+        })
+    }
+
+    /// Call the given function with each record.
+    pub fn from_fn(callback: impl FnMut(&AuditRecord) + Send + 'static) -> Self {
+        Self {
+            sink: AuditSink::Callback(Box::new(callback)),
+            record_text: false,
+        }
+    }
+
+    /// Also record what is typed into text fields.
+    ///
+    /// Password fields are masked by egui, so only their length is recorded either way.
+    pub fn with_text(mut self, record_text: bool) -> Self {
+        self.record_text = record_text;
+        self
+    }
+
+    fn write(&mut self, record: &AuditRecord) {
+        match &mut self.sink {
+            AuditSink::Tracing => {
+                tracing::info!(
+                    target: "eterm::audit",
+                    client_id = record.client_id,
+                    addr = %record.addr,
+                    "{:?}",
+                    record.event
+                );
+            }
+            AuditSink::JsonLines(writer) => {
+                let result = serde_json::to_writer(&mut *writer, record)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| {
+                        writer.write_all(b"\n")?;
+                        writer.flush()?;
+                        Ok(())
+                    });
+                if let Err(err) = result {
+                    tracing::error!("Failed to write eterm audit log: {}", err);
+                }
+            }
+            AuditSink::Callback(callback) => {
+                callback(record);
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// Turns the input and output of one client session into [`AuditRecord`]s.
+pub(crate) struct SessionAudit {
+    log: Arc<Mutex<AuditLog>>,
+    client_id: u64,
+    addr: SocketAddr,
+    record_text: bool,
+
+    /// The widget whose value the operator is currently changing, with its text.
+    /// Recorded once they are done with it.
+    pending_change: Option<AuditWidget>,
+}
+
+impl SessionAudit {
+    pub(crate) fn new(log: Arc<Mutex<AuditLog>>, client_id: u64, addr: SocketAddr) -> Self {
+        let record_text = log.lock().record_text;
+        Self {
+            log,
+            client_id,
+            addr,
+            record_text,
+            pending_change: None,
+        }
+    }
+
+    /// After a hand-off, the connection controls another session.
+    pub(crate) fn set_client_id(&mut self, client_id: u64) {
+        self.client_id = client_id;
+    }
+
+    pub(crate) fn connected(&mut self) {
+        self.record(AuditEvent::Connected);
+    }
+
+    pub(crate) fn disconnected(&mut self) {
+        self.flush_pending_change();
+        self.record(AuditEvent::Disconnected);
+    }
+
+    /// Call after each egui pass with the events that went into it, and what came out.
+    pub(crate) fn on_pass(&mut self, input_events: &[egui::Event], output: &egui::PlatformOutput) {
+        for event in &output.events {
+            match event {
+                egui::output::OutputEvent::Clicked(info) => {
+                    self.flush_pending_change();
+                    let widget = self.widget(info);
+                    self.record(AuditEvent::Clicked { widget });
+                }
+                egui::output::OutputEvent::DoubleClicked(info) => {
+                    self.flush_pending_change();
+                    let widget = self.widget(info);
+                    self.record(AuditEvent::DoubleClicked { widget });
+                }
+                egui::output::OutputEvent::TripleClicked(info) => {
+                    self.flush_pending_change();
+                    let widget = self.widget(info);
+                    self.record(AuditEvent::TripleClicked { widget });
+                }
+                egui::output::OutputEvent::ValueChanged(info) => {
+                    let widget = AuditWidget::from(info);
+                    if let Some(pending) = &self.pending_change {
+                        if !pending.is_same_widget(&widget) {
+                            self.flush_pending_change();
+                        }
+                    }
+                    self.pending_change = Some(widget);
+                }
+                egui::output::OutputEvent::FocusGained(_)
+                | egui::output::OutputEvent::TextSelectionChanged(_) => {}
+            }
+        }
+
+        // Done editing?
+        let done = input_events.iter().any(|event| {
+            matches!(
+                event,
+                egui::Event::Key {
+                    key: egui::Key::Enter | egui::Key::Tab,
+                    pressed: true,
+                    ..
+                } | egui::Event::PointerButton { pressed: false, .. }
+            )
+        });
+        if done {
+            self.flush_pending_change();
+        }
+    }
+
+    /// Without the text, unless we record text.
+    fn widget(&self, info: &egui::WidgetInfo) -> AuditWidget {
+        let mut widget = AuditWidget::from(info);
+        if !self.record_text {
+            widget.text = None;
+        }
+        widget
+    }
+
+    fn flush_pending_change(&mut self) {
+        if let Some(mut widget) = self.pending_change.take() {
+            let len = widget
+                .text
+                .as_deref()
+                .map_or(0, |text| text.chars().count());
+            if !self.record_text {
+                widget.text = None;
+            }
+            let event = if widget.typ == egui::WidgetType::TextEdit {
+                AuditEvent::TextCommitted {
+                    text: widget.text.clone(),
+                    len,
+                    widget,
+                }
+            } else {
+                AuditEvent::ValueChanged { widget }
+            };
+            self.record(event);
+        }
+    }
+
+    fn record(&mut self, event: AuditEvent) {
+        let record = AuditRecord {
+            time: std::time::UNIX_EPOCH
+                .elapsed()
+                .unwrap_or_default()
+                .as_secs_f64(),
+            client_id: self.client_id,
+            addr: self.addr,
+            event,
+        };
+        self.log.lock().write(&record);
+    }
+}
+
+#[cfg(test)]
+fn audit_text_session(record_text: bool) -> AuditEvent {
+    use egui::{output::OutputEvent, WidgetInfo};
+
+    let records = Arc::new(Mutex::new(vec![]));
+    let log = AuditLog::from_fn({
+        let records = records.clone();
+        move |record| records.lock().push(record.event.clone())
+    })
+    .with_text(record_text);
+    let mut audit = SessionAudit::new(
+        Arc::new(Mutex::new(log)),
+        7,
+        "127.0.0.1:1234".parse().unwrap(),
+    );
+    audit.on_pass(
+        &[],
+        &egui::PlatformOutput {
+            events: vec![OutputEvent::ValueChanged(WidgetInfo::text_edit(
+                "", "hunter2",
+            ))],
+            ..Default::default()
+        },
+    );
+    audit.disconnected();
+
+    let records = records.lock();
+    records[0].clone()
+}
+
+#[test]
+fn test_session_audit_text() {
+    match audit_text_session(false) {
+        AuditEvent::TextCommitted { widget, text, len } => {
+            assert_eq!(widget.text, None);
+            assert_eq!(text, None);
+            assert_eq!(len, 7);
+        }
+        event => panic!("Unexpected {:?}", event),
+    }
+
+    match audit_text_session(true) {
+        AuditEvent::TextCommitted { widget, text, len } => {
+            assert_eq!(widget.text.as_deref(), Some("hunter2"));
+            assert_eq!(text.as_deref(), Some("hunter2"));
+            assert_eq!(len, 7);
+        }
+        event => panic!("Unexpected {:?}", event),
+    }
+}
+
+#[test]
+fn test_session_audit() {
+    use egui::{output::OutputEvent, WidgetInfo, WidgetType};
+
+    let records = Arc::new(Mutex::new(vec![]));
+    let log = AuditLog::from_fn({
+        let records = records.clone();
+        move |record| records.lock().push(record.event.clone())
+    });
+    let mut audit = SessionAudit::new(
+        Arc::new(Mutex::new(log)),
+        7,
+        "127.0.0.1:1234".parse().unwrap(),
+    );
+
+    let output = |events: Vec<OutputEvent>| egui::PlatformOutput {
+        events,
+        ..Default::default()
+    };
+    let enter = egui::Event::Key {
+        key: egui::Key::Enter,
+        pressed: true,
+        modifiers: Default::default(),
+    };
+
+    audit.connected();
+    audit.on_pass(
+        &[],
+        &output(vec![OutputEvent::Clicked(WidgetInfo::labeled(
+            WidgetType::Button,
+            "Restart",
+        ))]),
+    );
+    for text in ["h", "hi"] {
+        audit.on_pass(
+            &[],
+            &output(vec![OutputEvent::ValueChanged(WidgetInfo::text_edit(
+                "", text,
+            ))]),
+        );
+    }
+    audit.on_pass(&[enter], &output(vec![]));
+    audit.on_pass(
+        &[],
+        &output(vec![OutputEvent::ValueChanged(WidgetInfo::slider(
+            0.5, "Volume",
+        ))]),
+    );
+    audit.disconnected();
+
+    let records = records.lock();
+    assert_eq!(records.len(), 5);
+    assert_eq!(records[0], AuditEvent::Connected);
+    assert!(
+        matches!(&records[1], AuditEvent::Clicked { widget } if widget.label.as_deref() == Some("Restart"))
+    );
+    assert!(matches!(
+        &records[2],
+        AuditEvent::TextCommitted {
+            text: None,
+            len: 2,
+            ..
+        }
+    ));
+    assert!(
+        matches!(&records[3], AuditEvent::ValueChanged { widget } if widget.value == Some(0.5))
+    );
+    assert_eq!(records[4], AuditEvent::Disconnected);
+}
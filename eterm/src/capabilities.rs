@@ -0,0 +1,198 @@
+//! What a client tells the server about itself when it connects:
+//! which viewer it is, what it runs on and what it prefers.
+//!
+//! From within `do_ui`, use [`Capabilities::of`]:
+//!
+//! ```
+//! # let egui_ctx = egui::Context::default();
+//! # let _ = egui_ctx.run(Default::default(), |egui_ctx| {
+//! let capabilities = eterm::Capabilities::of(egui_ctx);
+//! if let Some(theme) = capabilities.theme {
+//!     egui_ctx.set_visuals(theme.visuals());
+//! }
+//! egui::CentralPanel::default().show(egui_ctx, |ui| {
+//!     let shortcut = if capabilities.os == eterm::capabilities::Os::Mac {
+//!         "⌘S"
+//!     } else {
+//!         "Ctrl+S"
+//!     };
+//!     ui.label(format!("Save with {}", shortcut));
+//! });
+//! # });
+//! ```
+
+/// Longest name or version string we keep.
+const MAX_STRING_LEN: usize = 64;
+
+/// Most protocol features we keep.
+const MAX_FEATURES: usize = 64;
+
+/// The optional parts of the protocol this version of eterm supports,
+/// for [`Capabilities::features`].
+pub const FEATURES: &[&str] = &[
+    "clipboard",
+    "custom",
+    "open_url",
+    "presence",
+    "spectate",
+    "transfer",
+];
+
+/// The operating system of a client.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub enum Os {
+    /// The client didn't say, or we don't know it.
+    #[default]
+    Unknown,
+    Android,
+    IOS,
+    /// Linux or another Unix.
+    Nix,
+    Mac,
+    Windows,
+    Web,
+}
+
+impl Os {
+    /// The operating system we were compiled for.
+    pub fn current() -> Self {
+        if cfg!(target_arch = "wasm32") {
+            Self::Web
+        } else if cfg!(target_os = "android") {
+            Self::Android
+        } else if cfg!(target_os = "ios") {
+            Self::IOS
+        } else if cfg!(target_os = "macos") {
+            Self::Mac
+        } else if cfg!(target_os = "windows") {
+            Self::Windows
+        } else if cfg!(unix) {
+            Self::Nix
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// Dark or light mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    /// The default egui look for this theme.
+    pub fn visuals(self) -> egui::Visuals {
+        match self {
+            Self::Dark => egui::Visuals::dark(),
+            Self::Light => egui::Visuals::light(),
+        }
+    }
+}
+
+/// What a client tells the server about itself when it connects.
+///
+/// Set with [`crate::Client::set_capabilities`], read with [`Self::of`] or
+/// [`crate::Server::capabilities`].
+/// Everything in here comes from the client, so treat it as a hint.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Capabilities {
+    /// E.g. `"eterm_viewer"`. Empty if the client didn't say.
+    pub viewer_name: String,
+
+    /// E.g. `"0.0.1"`.
+    pub viewer_version: String,
+
+    pub os: Os,
+
+    /// The theme the user prefers, if any.
+    pub theme: Option<Theme>,
+
+    /// Physical pixels per point of the client's screen.
+    pub pixels_per_point: Option<f32>,
+
+    /// The largest texture the client can paint, in pixels.
+    pub max_texture_side: Option<usize>,
+
+    /// The optional protocol features the client supports, see [`FEATURES`].
+    ///
+    /// The server doesn't send clipboard contents, urls, files or custom messages
+    /// to a client without the matching feature.
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// A viewer with the given name and version, running on this computer
+    /// and supporting all [`FEATURES`].
+    pub fn new(viewer_name: impl Into<String>, viewer_version: impl Into<String>) -> Self {
+        Self {
+            viewer_name: viewer_name.into(),
+            viewer_version: viewer_version.into(),
+            os: Os::current(),
+            theme: None,
+            pixels_per_point: None,
+            max_texture_side: None,
+            features: FEATURES.iter().map(|&feature| feature.to_owned()).collect(),
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// The capabilities of the client whose ui is currently being shown.
+    ///
+    /// Call this from within the `do_ui` closure.
+    /// A client that hasn't told us anything has [`Capabilities::default`].
+    pub fn of(egui_ctx: &egui::Context) -> Self {
+        egui_ctx.data().get_temp(Self::id()).unwrap_or_default()
+    }
+
+    pub(crate) fn set(&self, egui_ctx: &egui::Context) {
+        egui_ctx.data().insert_temp(Self::id(), self.clone());
+    }
+
+    fn id() -> egui::Id {
+        egui::Id::new("eterm::Capabilities")
+    }
+
+    /// Make untrusted capabilities safe to keep and show.
+    pub(crate) fn sanitize(&mut self) {
+        sanitize_string(&mut self.viewer_name);
+        sanitize_string(&mut self.viewer_version);
+        if !matches!(self.pixels_per_point, Some(ppp) if (0.1..=16.0).contains(&ppp)) {
+            self.pixels_per_point = None;
+        }
+        self.features.truncate(MAX_FEATURES);
+        for feature in &mut self.features {
+            sanitize_string(feature);
+        }
+    }
+}
+
+fn sanitize_string(s: &mut String) {
+    *s = s
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_STRING_LEN)
+        .collect();
+}
+
+#[test]
+fn test_sanitize_capabilities() {
+    let mut capabilities = Capabilities {
+        viewer_name: "evil\u{1b}[2J".repeat(100),
+        pixels_per_point: Some(f32::NAN),
+        features: vec!["transfer".to_owned(); 1000],
+        ..Capabilities::new("", "")
+    };
+    capabilities.sanitize();
+    assert_eq!(capabilities.viewer_name.chars().count(), MAX_STRING_LEN);
+    assert!(!capabilities.viewer_name.contains('\u{1b}'));
+    assert_eq!(capabilities.pixels_per_point, None);
+    assert_eq!(capabilities.features.len(), MAX_FEATURES);
+    assert!(capabilities.has_feature("transfer"));
+}
@@ -1,5 +1,16 @@
-use crate::{ClientToServerMessage, EtermFrame, ServerToClientMessage, TcpEndpoint};
-use egui::{util::History, RawInput};
+use crate::{
+    capabilities::Capabilities,
+    clipboard::{Clipboard, ClipboardSync},
+    custom::CustomHandlers,
+    open_url::OpenUrlPolicy,
+    transfer::{
+        Direction, Pacer, ReceivedFile, TransferId, TransferProgress, Transfers,
+        DEFAULT_MAX_DOWNLOAD_SIZE, MAX_UPLOAD_SIZE, UPLOAD_BYTES_PER_SECOND,
+    },
+    ClientId, ClientToServerMessage, CustomMessage, EtermFrame, OutgoingInput,
+    ServerToClientMessage, SessionInfo, TcpEndpoint,
+};
+use egui::{output::OpenUrl, util::History, RawInput};
 use parking_lot::Mutex;
 use std::sync::{
     atomic::{AtomicBool, Ordering::SeqCst},
@@ -7,22 +18,72 @@ use std::sync::{
     Arc,
 };
 
+/// Called from the network thread when there is something new for [`Client::update`].
+type WakeCallback = Arc<dyn Fn() + Send + Sync>;
+
+/// What we tell the server on every (re)connect.
+pub(crate) struct Introduction {
+    pub(crate) capabilities: Capabilities,
+    pub(crate) name: Option<String>,
+    pub(crate) spectating: Option<ClientId>,
+}
+
+impl Introduction {
+    pub(crate) fn new(capabilities: Capabilities) -> Self {
+        Self {
+            capabilities,
+            name: None,
+            spectating: None,
+        }
+    }
+
+    pub(crate) fn messages(&self) -> Vec<ClientToServerMessage> {
+        let mut messages = vec![ClientToServerMessage::Hello(self.capabilities.clone())];
+        if let Some(name) = &self.name {
+            messages.push(ClientToServerMessage::SetName { name: name.clone() });
+        }
+        if self.spectating.is_some() {
+            messages.push(ClientToServerMessage::Spectate {
+                client_id: self.spectating,
+            });
+        }
+        messages
+    }
+}
+
+/// What [`Client`] asks the network thread to send.
+enum Outgoing {
+    Input(OutgoingInput),
+    Message(ClientToServerMessage),
+    /// Nothing to send, but have a look at the transfers (and if we are still alive).
+    Wake,
+}
+
 pub struct Client {
     addr: String,
     connected: Arc<AtomicBool>,
     alive: Arc<AtomicBool>,
-    outgoing_msg_tx: mpsc::Sender<ClientToServerMessage>,
+    outgoing_tx: mpsc::Sender<Outgoing>,
     incoming_msg_rx: mpsc::Receiver<ServerToClientMessage>,
     latest_frame: Option<EtermFrame>,
+    session_info: Option<SessionInfo>,
+    introduction: Arc<Mutex<Introduction>>,
+    custom_handlers: CustomHandlers<()>,
+    clipboard: Mutex<ClipboardSync>,
+    open_url_policy: OpenUrlPolicy,
+    url_requests: Vec<OpenUrl>,
+    transfers: Arc<Mutex<Transfers>>,
     bandwidth_history: Arc<Mutex<History<f32>>>,
     frame_size_history: Arc<Mutex<History<f32>>>,
     latency_history: History<f32>,
     frame_history: History<()>,
+    wake_callback: Arc<Mutex<Option<WakeCallback>>>,
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
         self.alive.store(false, SeqCst);
+        self.outgoing_tx.send(Outgoing::Wake).ok();
     }
 }
 
@@ -33,25 +94,50 @@ impl Client {
     /// eterm::Client::new("127.0.0.1:8580".to_owned());
     /// ```
     pub fn new(addr: String) -> Self {
+        Self::with_capabilities(addr, Capabilities::new("eterm", env!("CARGO_PKG_VERSION")))
+    }
+
+    /// Like [`Self::new`], but say hello with these [`Capabilities`] from the start,
+    /// instead of changing them with [`Self::set_capabilities`] once we may already be connected.
+    ///
+    /// ``` no_run
+    /// let capabilities = eterm::Capabilities::new("my_viewer", "0.1.0");
+    /// eterm::Client::with_capabilities("127.0.0.1:8580".to_owned(), capabilities);
+    /// ```
+    pub fn with_capabilities(addr: String, capabilities: Capabilities) -> Self {
         let alive = Arc::new(AtomicBool::new(true));
         let connected = Arc::new(AtomicBool::new(false));
-        let mut bandwidth_history = Arc::new(Mutex::new(History::new(0..200, 2.0)));
-        let mut frame_size_history = Arc::new(Mutex::new(History::new(1..100, 0.5)));
+        let bandwidth_history = Arc::new(Mutex::new(History::new(0..200, 2.0)));
+        let frame_size_history = Arc::new(Mutex::new(History::new(1..100, 0.5)));
+        let wake_callback: Arc<Mutex<Option<WakeCallback>>> = Default::default();
+        let introduction = Arc::new(Mutex::new(Introduction::new(capabilities)));
+        let transfers = Arc::new(Mutex::new(Transfers::new(
+            Direction::Upload,
+            DEFAULT_MAX_DOWNLOAD_SIZE,
+        )));
 
-        let (outgoing_msg_tx, mut outgoing_msg_rx) = mpsc::channel();
-        let (mut incoming_msg_tx, incoming_msg_rx) = mpsc::channel();
+        let (outgoing_tx, outgoing_rx) = mpsc::channel();
+        let (incoming_msg_tx, incoming_msg_rx) = mpsc::channel();
 
         let client = Self {
             addr: addr.clone(),
             connected: connected.clone(),
             alive: alive.clone(),
-            outgoing_msg_tx,
+            outgoing_tx: outgoing_tx.clone(),
             incoming_msg_rx,
             latest_frame: Default::default(),
+            session_info: None,
+            introduction: introduction.clone(),
+            custom_handlers: Default::default(),
+            clipboard: Default::default(),
+            open_url_policy: Default::default(),
+            url_requests: vec![],
+            transfers: transfers.clone(),
             bandwidth_history: bandwidth_history.clone(),
             frame_size_history: frame_size_history.clone(),
             latency_history: History::new(1..100, 1.0),
             frame_history: History::new(2..100, 1.0),
+            wake_callback: wake_callback.clone(),
         };
 
         std::thread::spawn(move || {
@@ -60,14 +146,24 @@ impl Client {
                 match std::net::TcpStream::connect(&addr) {
                     Ok(tcp_stream) => {
                         tracing::info!("Connected!");
-                        connected.store(true, SeqCst);
-                        if let Err(err) = run(
-                            tcp_stream,
-                            &mut outgoing_msg_rx,
-                            &mut incoming_msg_tx,
-                            &mut bandwidth_history,
-                            &mut frame_size_history,
-                        ) {
+                        let introduction_messages = {
+                            // Under the lock, so that changes are either in here or queued:
+                            let introduction = introduction.lock();
+                            connected.store(true, SeqCst);
+                            introduction.messages()
+                        };
+                        wake(&wake_callback);
+                        let connection = Connection {
+                            alive: &alive,
+                            outgoing_tx: &outgoing_tx,
+                            outgoing_rx: &outgoing_rx,
+                            incoming_msg_tx: &incoming_msg_tx,
+                            bandwidth_history: &bandwidth_history,
+                            frame_size_history: &frame_size_history,
+                            transfers: &transfers,
+                            wake_callback: &wake_callback,
+                        };
+                        if let Err(err) = connection.run(&tcp_stream, introduction_messages) {
                             tracing::info!(
                                 "Connection lost: {}",
                                 crate::error_display_chain(err.as_ref())
@@ -75,7 +171,12 @@ impl Client {
                         } else {
                             tracing::info!("Connection closed.",);
                         }
-                        connected.store(false, SeqCst);
+                        {
+                            let _introduction = introduction.lock();
+                            connected.store(false, SeqCst);
+                        }
+                        transfers.lock().clear();
+                        wake(&wake_callback);
                     }
                     Err(err) => {
                         tracing::debug!("Failed to connect to {}: {}", addr, err);
@@ -98,16 +199,298 @@ impl Client {
         self.connected.load(SeqCst)
     }
 
-    pub fn send_input(&self, raw_input: RawInput) {
-        self.outgoing_msg_tx
-            .send(ClientToServerMessage::Input {
+    /// Register a callback that is called from the network thread whenever
+    /// a new frame arrives, or when we connect or disconnect.
+    ///
+    /// Use this to wake up your render loop (e.g. by posting a winit user event
+    /// or calling [`egui::Context::request_repaint`]) and then call [`Self::update`].
+    ///
+    /// ``` no_run
+    /// let client = eterm::Client::new("127.0.0.1:8580".to_owned());
+    /// let egui_ctx = egui::Context::default();
+    /// client.set_wake_callback({
+    ///     let egui_ctx = egui_ctx.clone();
+    ///     move || egui_ctx.request_repaint()
+    /// });
+    /// ```
+    pub fn set_wake_callback(&self, wake_callback: impl Fn() + Send + 'static) {
+        let wake_callback = Mutex::new(wake_callback);
+        *self.wake_callback.lock() = Some(Arc::new(move || (wake_callback.lock())()));
+    }
+
+    /// Send input to the server.
+    ///
+    /// Only new events and changes to the input state (screen size, focus, …) are sent,
+    /// so it is fine to call this every frame.
+    ///
+    /// Clipboard events are filtered according to our clipboard settings
+    /// (see [`Self::set_clipboard_enabled`]).
+    ///
+    /// Dropped files are uploaded in the background (see [`Self::upload`]).
+    pub fn send_input(&self, mut raw_input: RawInput) {
+        self.clipboard.lock().filter_events(&mut raw_input.events);
+        for file in std::mem::take(&mut raw_input.dropped_files) {
+            self.upload_dropped_file(file);
+        }
+        self.outgoing_tx
+            .send(Outgoing::Input(OutgoingInput {
                 raw_input,
                 client_time: now(),
-                //points_per_pixel: self.points_per_pixel,
-            })
+            }))
             .ok();
     }
 
+    /// Watch the session of another client instead of using our own,
+    /// or `None` to go back to our own session.
+    ///
+    /// While spectating, the frames of the other session are shown
+    /// (scaled down to fit if our screen is smaller), and our input is ignored.
+    /// This is remembered across reconnects.
+    pub fn spectate(&self, client_id: Option<ClientId>) {
+        self.introduce(|introduction| {
+            introduction.spectating = client_id;
+            ClientToServerMessage::Spectate { client_id }
+        });
+    }
+
+    /// Tell the server who we are and what we can do, see [`Capabilities`].
+    ///
+    /// By default we are `"eterm"` on [`crate::capabilities::Os::current`] with all
+    /// [`crate::capabilities::FEATURES`], with no theme preference, scale or texture size.
+    ///
+    /// This is remembered across reconnects.
+    /// If you know them up front, use [`Self::with_capabilities`] instead.
+    pub fn set_capabilities(&self, capabilities: Capabilities) {
+        self.introduce(|introduction| {
+            introduction.capabilities = capabilities.clone();
+            ClientToServerMessage::Hello(capabilities)
+        });
+    }
+
+    /// See [`Self::set_capabilities`].
+    pub fn capabilities(&self) -> Capabilities {
+        self.introduction.lock().capabilities.clone()
+    }
+
+    /// What to call us when the server shows our pointer to others.
+    ///
+    /// This is remembered across reconnects.
+    pub fn set_name(&self, name: impl Into<String>) {
+        let name = name.into();
+        self.introduce(|introduction| {
+            introduction.name = Some(name.clone());
+            ClientToServerMessage::SetName { name }
+        });
+    }
+
+    /// Give control of our session to one of its [`SessionInfo::spectators`].
+    ///
+    /// We become a spectator of the session we gave away.
+    /// The session keeps its [`ClientId`], so we get the id of the spectator, and it gets ours.
+    pub fn hand_off(&self, to: ClientId) {
+        self.send_message(ClientToServerMessage::HandOff { to });
+    }
+
+    /// What the server last told us about our session and its spectators.
+    pub fn session_info(&self) -> Option<&SessionInfo> {
+        self.session_info.as_ref()
+    }
+
+    /// Send a value on an application-defined channel,
+    /// which the server receives with [`crate::Server::on_custom`].
+    ///
+    /// Like input, this is queued while we are disconnected.
+    ///
+    /// # Errors
+    /// If the value can't be encoded,
+    /// or is larger than [`crate::MAX_CLIENT_MESSAGE_SIZE`].
+    pub fn send_custom<T: serde::Serialize + ?Sized>(
+        &self,
+        channel: impl Into<String>,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let message = CustomMessage::new(channel, value)?;
+        anyhow::ensure!(
+            message.payload.len() as u64 <= crate::MAX_CLIENT_MESSAGE_SIZE,
+            "custom message is too large: {:.1} MB",
+            message.payload.len() as f32 * 1e-6
+        );
+        self.send_message(ClientToServerMessage::Custom(message));
+        Ok(())
+    }
+
+    /// Handle the values the server sends on this channel
+    /// (see [`crate::Server::send_custom`]).
+    ///
+    /// The handler is called from [`Self::update`].
+    /// Messages that can't be decoded as a `T` are logged and dropped.
+    pub fn on_custom<T: serde::de::DeserializeOwned>(
+        &mut self,
+        channel: impl Into<String>,
+        mut handler: impl FnMut(T) + Send + 'static,
+    ) {
+        self.custom_handlers
+            .insert(channel.into(), move |(), value| handler(value));
+    }
+
+    /// Allow or deny the server access to our clipboard. On by default.
+    ///
+    /// When off, copy, cut and paste events are not sent,
+    /// and text the server copies is ignored.
+    pub fn set_clipboard_enabled(&self, enabled: bool) {
+        self.clipboard.lock().set_enabled(enabled);
+    }
+
+    /// Does the server have access to our clipboard?
+    pub fn clipboard_enabled(&self) -> bool {
+        self.clipboard.lock().enabled()
+    }
+
+    /// Drop pasted and copied text larger than this many bytes.
+    ///
+    /// Defaults to [`crate::clipboard::DEFAULT_MAX_CLIPBOARD_SIZE`].
+    pub fn set_max_clipboard_size(&self, max_size: usize) {
+        self.clipboard.lock().set_max_size(max_size);
+    }
+
+    /// Use this clipboard instead of leaving the clipboard to the integration.
+    ///
+    /// Text the server copies is then written to it (and removed from [`EtermFrame::platform_output`]),
+    /// and [`Self::paste`] reads from it.
+    pub fn set_clipboard(&self, clipboard: impl Clipboard + 'static) {
+        self.clipboard.lock().set_clipboard(Box::new(clipboard));
+    }
+
+    /// Paste the contents of the clipboard set with [`Self::set_clipboard`]
+    /// with the next [`Self::send_input`].
+    pub fn paste(&self) {
+        self.clipboard.lock().paste();
+    }
+
+    /// Which urls the server may make us open (see [`EtermFrame::platform_output`]).
+    ///
+    /// The default is [`OpenUrlPolicy::Block`].
+    /// Urls that are not allowed are removed from the output in [`Self::update`].
+    pub fn set_open_url_policy(&mut self, policy: OpenUrlPolicy) {
+        self.open_url_policy = policy;
+    }
+
+    /// The urls to ask the user about, with [`OpenUrlPolicy::Ask`].
+    ///
+    /// If the user agrees, open them yourself.
+    pub fn take_url_requests(&mut self) -> Vec<OpenUrl> {
+        std::mem::take(&mut self.url_requests)
+    }
+
+    /// Send a file to the server, where it shows up in
+    /// [`egui::RawInput::dropped_files`] once it has been received in full.
+    ///
+    /// It is sent in chunks, in the background. Uploads are dropped if the connection is lost.
+    ///
+    /// # Errors
+    /// If the file is larger than [`MAX_UPLOAD_SIZE`].
+    pub fn upload(&self, name: impl Into<String>, bytes: Vec<u8>) -> anyhow::Result<TransferId> {
+        let name = name.into();
+        anyhow::ensure!(
+            bytes.len() as u64 <= MAX_UPLOAD_SIZE,
+            "Not uploading {:?}: it is too large ({:.1} MB)",
+            name,
+            bytes.len() as f64 * 1e-6
+        );
+        let id = self.transfers.lock().send(name, bytes);
+        self.outgoing_tx.send(Outgoing::Wake).ok();
+        Ok(id)
+    }
+
+    /// Files dropped by path are read by the network thread as they are sent.
+    fn upload_dropped_file(&self, file: egui::DroppedFile) {
+        let name = match &file.path {
+            Some(path) if file.name.is_empty() => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            _ => file.name,
+        };
+        match (file.bytes, file.path) {
+            (Some(bytes), _) => {
+                if let Err(err) = self.upload(name, bytes.to_vec()) {
+                    tracing::error!("{}", err);
+                }
+            }
+            (None, Some(path)) => {
+                let open = |path: &std::path::Path| {
+                    let file = std::fs::File::open(path)?;
+                    let size = file.metadata()?.len();
+                    std::io::Result::Ok((file, size))
+                };
+                match open(&path) {
+                    Ok((_, size)) if size > MAX_UPLOAD_SIZE => {
+                        tracing::error!(
+                            "Not uploading {:?}: it is too large ({:.1} MB)",
+                            path,
+                            size as f64 * 1e-6
+                        );
+                    }
+                    Ok((file, size)) => {
+                        self.transfers.lock().send_file(name, file, size);
+                        self.outgoing_tx.send(Outgoing::Wake).ok();
+                    }
+                    Err(err) => {
+                        tracing::error!("Failed to read dropped file {:?}: {}", path, err);
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Files the server has sent us with [`crate::Server::send_file`].
+    ///
+    /// Use [`ReceivedFile::safe_file_name`] when saving them.
+    pub fn take_downloads(&self) -> Vec<ReceivedFile> {
+        self.transfers.lock().take_received()
+    }
+
+    /// Refuse all downloads. They are enabled by default.
+    pub fn set_downloads_enabled(&self, enabled: bool) {
+        self.transfers.lock().set_incoming_enabled(enabled);
+    }
+
+    /// Refuse downloads larger than this many bytes.
+    ///
+    /// Defaults to [`DEFAULT_MAX_DOWNLOAD_SIZE`].
+    pub fn set_max_download_size(&self, max_size: u64) {
+        self.transfers.lock().set_max_incoming_size(max_size);
+    }
+
+    /// Our uploads and downloads in progress.
+    pub fn transfers(&self) -> Vec<TransferProgress> {
+        self.transfers.lock().progress()
+    }
+
+    /// Stop an upload or a download.
+    ///
+    /// Returns `false` if there is no such transfer in progress.
+    pub fn cancel_transfer(&self, id: TransferId) -> bool {
+        let cancelled = self.transfers.lock().cancel(id, "Cancelled by the client");
+        self.outgoing_tx.send(Outgoing::Wake).ok();
+        cancelled
+    }
+
+    fn send_message(&self, message: ClientToServerMessage) {
+        self.outgoing_tx.send(Outgoing::Message(message)).ok();
+    }
+
+    /// Change what we tell the server on connect.
+    /// If we are connected, also tell it now with the returned message.
+    fn introduce(&self, change: impl FnOnce(&mut Introduction) -> ClientToServerMessage) {
+        let mut introduction = self.introduction.lock();
+        let message = change(&mut introduction);
+        if self.is_connected() {
+            self.send_message(message);
+        }
+    }
+
     /// Estimated bandwidth use (downstream).
     pub fn bytes_per_second(&self) -> f32 {
         self.bandwidth_history.lock().bandwidth().unwrap_or(0.0)
@@ -130,9 +513,12 @@ impl Client {
 
     /// Retrieved new events, and gives back what to do.
     ///
+    /// All pending frames are drained, and only the newest one is returned.
+    /// Texture updates and platform output of any skipped frames are merged into it.
+    ///
     /// Return `None` when there is nothing new.
     pub fn update(&mut self) -> Option<EtermFrame> {
-        if let Ok(msg) = self.incoming_msg_rx.try_recv() {
+        while let Ok(msg) = self.incoming_msg_rx.try_recv() {
             match msg {
                 ServerToClientMessage::Frame {
                     frame_index,
@@ -141,12 +527,17 @@ impl Client {
                     client_time,
                     textures_delta,
                 } => {
-                    self.latest_frame = Some(EtermFrame {
+                    let frame = EtermFrame {
                         frame_index,
                         platform_output,
                         clipped_net_mesh,
                         textures_delta,
-                    });
+                    };
+
+                    match &mut self.latest_frame {
+                        Some(latest_frame) => latest_frame.append(frame),
+                        None => self.latest_frame = Some(frame),
+                    }
 
                     if let Some(client_time) = client_time {
                         let rtt = (now() - client_time) as f32;
@@ -155,6 +546,13 @@ impl Client {
 
                     self.frame_history.add(now(), ());
                 }
+                ServerToClientMessage::SessionInfo(session_info) => {
+                    self.session_info = Some(session_info);
+                }
+                ServerToClientMessage::Custom(message) => {
+                    self.custom_handlers.handle((), &message);
+                }
+                ServerToClientMessage::Transfer(_) => {} // handled by the network thread
             }
         }
 
@@ -163,53 +561,327 @@ impl Client {
         self.latency_history.flush(now());
         self.frame_history.flush(now());
 
-        self.latest_frame.take()
+        let mut frame = self.latest_frame.take();
+        if let Some(frame) = &mut frame {
+            self.clipboard
+                .lock()
+                .handle_output(&mut frame.platform_output);
+            if let Some(request) = self.open_url_policy.apply(&mut frame.platform_output) {
+                self.url_requests.push(request);
+            }
+        }
+        frame
     }
 }
 
-fn run(
-    tcp_stream: std::net::TcpStream,
-    outgoing_msg_rx: &mut mpsc::Receiver<ClientToServerMessage>,
-    incoming_msg_tx: &mut mpsc::Sender<ServerToClientMessage>,
-    bandwidth_history: &mut Arc<Mutex<History<f32>>>,
-    frame_size_history: &mut Arc<Mutex<History<f32>>>,
-) -> anyhow::Result<()> {
-    use anyhow::Context as _;
+/// What the network thread shares with a connection.
+struct Connection<'a> {
+    alive: &'a AtomicBool,
+    outgoing_tx: &'a mpsc::Sender<Outgoing>,
+    outgoing_rx: &'a mpsc::Receiver<Outgoing>,
+    incoming_msg_tx: &'a mpsc::Sender<ServerToClientMessage>,
+    bandwidth_history: &'a Arc<Mutex<History<f32>>>,
+    frame_size_history: &'a Arc<Mutex<History<f32>>>,
+    transfers: &'a Arc<Mutex<Transfers>>,
+    wake_callback: &'a Arc<Mutex<Option<WakeCallback>>>,
+}
+
+impl Connection<'_> {
+    /// Send what we are asked to on this thread, while a reader thread blocks on the socket.
+    ///
+    /// Returns when the connection is lost or the [`Client`] is dropped.
+    fn run(
+        &self,
+        tcp_stream: &std::net::TcpStream,
+        introduction: Vec<ClientToServerMessage>,
+    ) -> anyhow::Result<()> {
+        use anyhow::Context as _;
+
+        let reader_done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let tcp_stream = tcp_stream.try_clone().context("TCP clone")?;
+            let reader_done = reader_done.clone();
+            let outgoing_tx = self.outgoing_tx.clone();
+            let incoming_msg_tx = self.incoming_msg_tx.clone();
+            let bandwidth_history = self.bandwidth_history.clone();
+            let frame_size_history = self.frame_size_history.clone();
+            let transfers = self.transfers.clone();
+            let wake_callback = self.wake_callback.clone();
+            std::thread::spawn(move || {
+                let result = receive(
+                    tcp_stream,
+                    &outgoing_tx,
+                    &incoming_msg_tx,
+                    &bandwidth_history,
+                    &frame_size_history,
+                    &transfers,
+                    &wake_callback,
+                );
+                // Let the sending side know we are done:
+                reader_done.store(true, SeqCst);
+                outgoing_tx.send(Outgoing::Wake).ok();
+                result
+            })
+        };
 
-    tcp_stream
-        .set_nonblocking(true)
-        .context("TCP set_nonblocking")?;
+        let mut tcp_endpoint = TcpEndpoint::new(tcp_stream.try_clone().context("TCP clone")?);
+        let result = self.send(&mut tcp_endpoint, introduction, &reader_done);
 
-    let mut tcp_endpoint = TcpEndpoint { tcp_stream };
+        // Stop the reader, if it is still going:
+        tcp_stream.shutdown(std::net::Shutdown::Both).ok();
+        let read_result = reader
+            .join()
+            .map_err(|_panic| anyhow::anyhow!("The receiving thread panicked"))?;
+
+        match result {
+            Ok(SendStop::ReaderDone) => read_result,
+            Ok(SendStop::Dropped) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn send(
+        &self,
+        tcp_endpoint: &mut TcpEndpoint,
+        introduction: Vec<ClientToServerMessage>,
+        reader_done: &AtomicBool,
+    ) -> anyhow::Result<SendStop> {
+        // What the server knows about our input state:
+        let mut input_state = RawInput::default();
+
+        let mut upload_pacer = Pacer::new(UPLOAD_BYTES_PER_SECOND, std::time::Instant::now());
+        let mut uploading = false;
+
+        for message in introduction {
+            tcp_endpoint.send_message(&message)?;
+        }
+
+        // The flags are set before the wake-up is sent, which we may drain below
+        // without blocking, so we check them both before and after blocking:
+        let stop = || {
+            if !self.alive.load(SeqCst) {
+                Some(SendStop::Dropped)
+            } else if reader_done.load(SeqCst) {
+                Some(SendStop::ReaderDone)
+            } else {
+                None
+            }
+        };
 
-    loop {
         loop {
-            match outgoing_msg_rx.try_recv() {
-                Ok(message) => {
-                    tcp_endpoint.send_message(&message)?;
-                }
-                Err(mpsc::TryRecvError::Empty) => break,
-                Err(mpsc::TryRecvError::Disconnected) => {
-                    return Ok(());
+            if let Some(stop) = stop() {
+                return Ok(stop);
+            }
+
+            // Block until there is something to send, or the next upload chunk is due:
+            let outgoing = if uploading {
+                self.outgoing_rx
+                    .recv_timeout(upload_pacer.time_until_ready())
+                    .ok()
+            } else {
+                self.outgoing_rx.recv().ok()
+            };
+
+            if let Some(stop) = stop() {
+                return Ok(stop);
+            }
+
+            for outgoing in outgoing.into_iter().chain(self.outgoing_rx.try_iter()) {
+                match outgoing {
+                    Outgoing::Input(input) => {
+                        if let Some(message) = input.encode(&mut input_state) {
+                            tcp_endpoint.send_message(&message)?;
+                        }
+                    }
+                    Outgoing::Message(message) => {
+                        tcp_endpoint.send_message(&message)?;
+                    }
+                    Outgoing::Wake => {}
                 }
             }
+
+            // Paced, so that the server doesn't throttle us (which would hold up the input too):
+            uploading = loop {
+                if !upload_pacer.ready(std::time::Instant::now()) {
+                    break true; // more later
+                }
+                let message = self.transfers.lock().next_message();
+                match message {
+                    Some(message) => {
+                        upload_pacer.consume(message.payload_len());
+                        tcp_endpoint.send_message(&ClientToServerMessage::Transfer(message))?;
+                    }
+                    None => break false,
+                }
+            };
         }
+    }
+}
+
+/// Why [`Connection::send`] stopped without an error.
+enum SendStop {
+    /// The [`Client`] was dropped.
+    Dropped,
+    /// The receiving thread stopped, e.g. because the server closed the connection.
+    ReaderDone,
+}
 
-        while let Some(packet) = tcp_endpoint.try_receive_packet().context("receive")? {
-            bandwidth_history.lock().add(now(), packet.len() as f32);
-            let message = crate::decode_message(&packet).context("decode")?;
+/// Block on the socket, handing what we receive to the [`Client`].
+fn receive(
+    tcp_stream: std::net::TcpStream,
+    outgoing_tx: &mpsc::Sender<Outgoing>,
+    incoming_msg_tx: &mpsc::Sender<ServerToClientMessage>,
+    bandwidth_history: &Mutex<History<f32>>,
+    frame_size_history: &Mutex<History<f32>>,
+    transfers: &Mutex<Transfers>,
+    wake_callback: &Mutex<Option<WakeCallback>>,
+) -> anyhow::Result<()> {
+    use anyhow::Context as _;
 
-            #[allow(irrefutable_let_patterns)]
-            if let ServerToClientMessage::Frame { .. } = &message {
-                frame_size_history.lock().add(now(), packet.len() as f32);
+    let mut tcp_endpoint = TcpEndpoint::new(tcp_stream);
+
+    loop {
+        let packet = match tcp_endpoint.try_receive_packet().context("receive")? {
+            Some(packet) => packet,
+            None => continue, // spurious wake-up of the blocking read
+        };
+        bandwidth_history.lock().add(now(), packet.len() as f32);
+        let message = crate::decode_message(packet).context("decode")?;
+
+        if let ServerToClientMessage::Frame { .. } = &message {
+            frame_size_history.lock().add(now(), packet.len() as f32);
+        }
+        if let ServerToClientMessage::Transfer(message) = message {
+            let mut transfers = transfers.lock();
+            transfers.on_message(message);
+            // It may have something to send back (e.g. a refusal):
+            outgoing_tx.send(Outgoing::Wake).ok();
+            if !transfers.has_received() {
+                continue; // nothing new for the app yet
             }
+        } else {
             incoming_msg_tx.send(message)?;
         }
+        wake(wake_callback);
+    }
+}
 
-        std::thread::sleep(std::time::Duration::from_millis(5));
+fn wake(wake_callback: &Mutex<Option<WakeCallback>>) {
+    // Not called under the lock, so that the callback may replace itself:
+    let wake_callback = wake_callback.lock().clone();
+    if let Some(wake_callback) = wake_callback {
+        wake_callback();
     }
 }
 
 fn now() -> f64 {
     std::time::UNIX_EPOCH.elapsed().unwrap().as_secs_f64()
 }
+
+#[test]
+fn test_wake_callback_can_replace_itself() {
+    use std::sync::atomic::AtomicUsize;
+
+    let slot: Arc<Mutex<Option<WakeCallback>>> = Default::default();
+    let calls = Arc::new(AtomicUsize::new(0));
+    *slot.lock() = Some(Arc::new({
+        let slot = slot.clone();
+        let calls = calls.clone();
+        move || {
+            calls.fetch_add(1, SeqCst);
+            *slot.lock() = Some(Arc::new(|| {}));
+        }
+    }));
+
+    wake(&slot);
+    wake(&slot);
+    assert_eq!(calls.load(SeqCst), 1);
+}
+
+#[test]
+fn test_introduction_is_sent_once() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = Client::new(listener.local_addr().unwrap().to_string());
+    client.set_name("Ada"); // probably not connected yet
+
+    // Blocking, so we don't have to poll:
+    let accept = || {
+        let (tcp_stream, _) = listener.accept().unwrap();
+        tcp_stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+        TcpEndpoint::new(tcp_stream)
+    };
+    let start = std::time::Instant::now();
+    let next_name = |endpoint: &mut TcpEndpoint| loop {
+        assert!(start.elapsed().as_secs() < 10, "Timeout");
+        if let Some(packet) = endpoint.try_receive_packet().unwrap() {
+            if let ClientToServerMessage::SetName { name } = crate::decode_message(packet).unwrap()
+            {
+                return name;
+            }
+        }
+    };
+
+    let mut endpoint = accept();
+    assert_eq!(next_name(&mut endpoint), "Ada");
+    client.set_name("Bob"); // connected now
+    assert_eq!(next_name(&mut endpoint), "Bob");
+
+    // The client reconnects, and introduces itself again:
+    drop(endpoint);
+    let mut endpoint = accept();
+    assert_eq!(next_name(&mut endpoint), "Bob");
+    client.set_name("Cy");
+    assert_eq!(next_name(&mut endpoint), "Cy");
+}
+
+#[test]
+fn test_client_round_trip() {
+    use std::sync::atomic::AtomicUsize;
+
+    let mut server = crate::Server::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = Client::new(addr.to_string());
+    let wakes = Arc::new(AtomicUsize::new(0));
+    client.set_wake_callback({
+        let wakes = wakes.clone();
+        move || {
+            wakes.fetch_add(1, SeqCst);
+        }
+    });
+    client.send_input(RawInput {
+        screen_rect: Some(egui::Rect::from_min_size(
+            egui::Pos2::ZERO,
+            egui::vec2(800.0, 600.0),
+        )),
+        ..Default::default()
+    });
+
+    let dropped_files = Arc::new(Mutex::new(vec![]));
+    let mut ui = |egui_ctx: &egui::Context, _: ClientId| {
+        dropped_files
+            .lock()
+            .extend(egui_ctx.input().raw.dropped_files.clone());
+    };
+
+    // Frames arrive, and wake us up:
+    let start = std::time::Instant::now();
+    while client.update().is_none() {
+        assert!(start.elapsed().as_secs() < 10, "Timeout");
+        server.show(&mut ui).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert!(wakes.load(SeqCst) > 0);
+
+    // An upload gets going even though we have no input to send:
+    client
+        .upload("config.toml", b"answer = 42".to_vec())
+        .unwrap();
+    while dropped_files.lock().is_empty() {
+        assert!(start.elapsed().as_secs() < 10, "Timeout");
+        server.show(&mut ui).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(dropped_files.lock()[0].name, "config.toml");
+}
@@ -0,0 +1,218 @@
+//! Clipboard synchronization between a client and the server.
+//!
+//! * Copy and cut: the client sends [`egui::Event::Copy`] or [`egui::Event::Cut`],
+//!   the server ui puts the selected text in [`egui::PlatformOutput::copied_text`],
+//!   and the client writes that to its clipboard.
+//! * Paste: the client sends the text of its clipboard as [`egui::Event::Paste`].
+//!
+//! Clipboard access is a permission of the client (see [`crate::Client::set_clipboard_enabled`]).
+//! When it is off, none of the above events are sent, and copied text from the server is ignored.
+//! Text larger than the maximum size is dropped in both directions, never truncated.
+//!
+//! By default the integration (e.g. `egui-winit`) reads and writes the system clipboard.
+//! Headless clients and tests can use [`MemoryClipboard`] instead:
+//!
+//! ``` no_run
+//! use eterm::clipboard::{Clipboard as _, MemoryClipboard};
+//!
+//! let mut clipboard = MemoryClipboard::default();
+//! let client = eterm::Client::new("127.0.0.1:8505".to_owned());
+//! client.set_clipboard(clipboard.clone());
+//!
+//! clipboard.set("Hello server!".to_owned());
+//! client.paste(); // sent with the next `send_input`
+//! ```
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Don't send or accept clipboard text larger than this many bytes by default.
+pub const DEFAULT_MAX_CLIPBOARD_SIZE: usize = 1024 * 1024;
+
+/// Somewhere to keep copied text.
+pub trait Clipboard: Send {
+    fn get(&mut self) -> Option<String>;
+    fn set(&mut self, text: String);
+}
+
+/// A clipboard that lives in memory, e.g. for tests on machines without a system clipboard.
+///
+/// Clones share the same contents.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryClipboard {
+    text: Arc<Mutex<Option<String>>>,
+}
+
+impl Clipboard for MemoryClipboard {
+    fn get(&mut self) -> Option<String> {
+        self.text.lock().clone()
+    }
+
+    fn set(&mut self, text: String) {
+        *self.text.lock() = Some(text);
+    }
+}
+
+/// The clipboard policy of a client.
+pub(crate) struct ClipboardSync {
+    enabled: bool,
+    max_size: usize,
+
+    /// If `None`, the integration handles the clipboard.
+    clipboard: Option<Box<dyn Clipboard>>,
+
+    /// Send this as a paste with the next input.
+    pending_paste: Option<String>,
+}
+
+impl Default for ClipboardSync {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size: DEFAULT_MAX_CLIPBOARD_SIZE,
+            clipboard: None,
+            pending_paste: None,
+        }
+    }
+}
+
+impl ClipboardSync {
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.pending_paste = None;
+        }
+    }
+
+    pub(crate) fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub(crate) fn set_clipboard(&mut self, clipboard: Box<dyn Clipboard>) {
+        self.clipboard = Some(clipboard);
+    }
+
+    /// Read our clipboard, to be pasted with the next input.
+    pub(crate) fn paste(&mut self) {
+        if !self.enabled {
+            tracing::debug!("Clipboard access is disabled; not pasting");
+            return;
+        }
+        if let Some(clipboard) = &mut self.clipboard {
+            self.pending_paste = clipboard.get();
+        }
+    }
+
+    /// Apply the policy to the events we are about to send, and add any pending paste.
+    pub(crate) fn filter_events(&mut self, events: &mut Vec<egui::Event>) {
+        if !self.enabled {
+            events.retain(|event| {
+                !matches!(
+                    event,
+                    egui::Event::Copy | egui::Event::Cut | egui::Event::Paste(_)
+                )
+            });
+            return;
+        }
+
+        if let Some(text) = self.pending_paste.take() {
+            events.push(egui::Event::Paste(text));
+        }
+
+        let max_size = self.max_size;
+        events.retain(|event| match event {
+            egui::Event::Paste(text) if text.len() > max_size => {
+                tracing::warn!(
+                    "Not pasting {} bytes: larger than the limit of {} bytes",
+                    text.len(),
+                    max_size
+                );
+                false
+            }
+            _ => true,
+        });
+    }
+
+    /// Handle the text the server copied.
+    ///
+    /// If we have a clipboard, it is written there and removed from the output.
+    /// Otherwise it is left in the output for the integration.
+    pub(crate) fn handle_output(&mut self, platform_output: &mut egui::PlatformOutput) {
+        let text = std::mem::take(&mut platform_output.copied_text);
+        if text.is_empty() {
+            return;
+        }
+
+        if !self.enabled {
+            tracing::debug!("Clipboard access is disabled; ignoring copied text");
+        } else if text.len() > self.max_size {
+            tracing::warn!(
+                "Ignoring {} bytes of copied text: larger than the limit of {} bytes",
+                text.len(),
+                self.max_size
+            );
+        } else if let Some(clipboard) = &mut self.clipboard {
+            clipboard.set(text);
+        } else {
+            platform_output.copied_text = text;
+        }
+    }
+}
+
+#[test]
+fn test_clipboard_sync() {
+    let copied = |text: &str| egui::PlatformOutput {
+        copied_text: text.to_owned(),
+        ..Default::default()
+    };
+
+    // Left to the integration by default:
+    let mut sync = ClipboardSync::default();
+    let mut output = copied("integration");
+    sync.handle_output(&mut output);
+    assert_eq!(output.copied_text, "integration");
+
+    let mut clipboard = MemoryClipboard::default();
+    sync.set_clipboard(Box::new(clipboard.clone()));
+    sync.set_max_size(8);
+
+    // Copy:
+    let mut output = copied("copied");
+    sync.handle_output(&mut output);
+    assert_eq!(output.copied_text, "");
+    assert_eq!(clipboard.get().as_deref(), Some("copied"));
+
+    // Too large to copy:
+    sync.handle_output(&mut copied("much too large"));
+    assert_eq!(clipboard.get().as_deref(), Some("copied"));
+
+    // Paste:
+    clipboard.set("pasted".to_owned());
+    sync.paste();
+    let mut events = vec![
+        egui::Event::Copy,
+        egui::Event::Paste("much too large".to_owned()),
+    ];
+    sync.filter_events(&mut events);
+    assert_eq!(
+        events,
+        vec![egui::Event::Copy, egui::Event::Paste("pasted".to_owned())]
+    );
+
+    // No access:
+    sync.set_enabled(false);
+    sync.paste();
+    let mut events = vec![
+        egui::Event::Cut,
+        egui::Event::Paste("secret".to_owned()),
+        egui::Event::Text("a".to_owned()),
+    ];
+    sync.filter_events(&mut events);
+    assert_eq!(events, vec![egui::Event::Text("a".to_owned())]);
+    sync.handle_output(&mut copied("overwrite"));
+    assert_eq!(clipboard.get().as_deref(), Some("pasted"));
+}
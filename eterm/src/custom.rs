@@ -0,0 +1,130 @@
+//! Application-defined messages sent over the eterm connection.
+//!
+//! ``` no_run
+//! #[derive(serde::Serialize, serde::Deserialize)]
+//! struct Bookmark {
+//!     url: String,
+//! }
+//!
+//! let mut server = eterm::Server::new("0.0.0.0:8505")?;
+//! server.on_custom("bookmark", |client_id, bookmark: Bookmark| {
+//!     println!("Client {} bookmarked {}", client_id, bookmark.url);
+//! });
+//!
+//! let client = eterm::Client::new("127.0.0.1:8505".to_owned());
+//! client.send_custom(
+//!     "bookmark",
+//!     &Bookmark {
+//!         url: "https://github.com/emilk/egui".to_owned(),
+//!     },
+//! )?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::collections::HashMap;
+
+/// A message on an application-defined channel.
+///
+/// The payload is the value encoded with `bincode`.
+/// The whole message is then compressed like all other eterm messages.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CustomMessage {
+    pub channel: String,
+    pub payload: Vec<u8>,
+}
+
+impl CustomMessage {
+    /// Encode a value for sending on the given channel.
+    ///
+    /// # Errors
+    /// If the value can't be serialized, or is larger than [`crate::MAX_SERVER_MESSAGE_SIZE`].
+    pub fn new<T: serde::Serialize + ?Sized>(
+        channel: impl Into<String>,
+        value: &T,
+    ) -> anyhow::Result<Self> {
+        use anyhow::Context as _;
+        use bincode::Options as _;
+        let payload = crate::bincode_options(crate::MAX_SERVER_MESSAGE_SIZE)
+            .serialize(value)
+            .context("bincode")?;
+        Ok(Self {
+            channel: channel.into(),
+            payload,
+        })
+    }
+
+    /// Decode the payload.
+    ///
+    /// # Errors
+    /// If the payload is not a `T`.
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        use anyhow::Context as _;
+        use bincode::Options as _;
+        crate::bincode_options(crate::MAX_SERVER_MESSAGE_SIZE)
+            .deserialize(&self.payload)
+            .with_context(|| format!("decoding custom message on channel {:?}", self.channel))
+    }
+}
+
+type CustomHandler<C> = Box<dyn FnMut(C, &CustomMessage) + Send>;
+
+/// Per-channel handlers of [`CustomMessage`]s, called with some context `C`
+/// (e.g. who sent the message).
+pub(crate) struct CustomHandlers<C> {
+    handlers: HashMap<String, CustomHandler<C>>,
+}
+
+impl<C> Default for CustomHandlers<C> {
+    fn default() -> Self {
+        Self {
+            handlers: Default::default(),
+        }
+    }
+}
+
+impl<C> CustomHandlers<C> {
+    /// Replaces any previous handler of the channel.
+    pub(crate) fn insert<T: serde::de::DeserializeOwned>(
+        &mut self,
+        channel: String,
+        mut handler: impl FnMut(C, T) + Send + 'static,
+    ) {
+        self.handlers.insert(
+            channel,
+            Box::new(move |context, message| match message.decode() {
+                Ok(value) => handler(context, value),
+                Err(err) => {
+                    tracing::warn!("{}", crate::error_display_chain(err.as_ref()));
+                }
+            }),
+        );
+    }
+
+    pub(crate) fn handle(&mut self, context: C, message: &CustomMessage) {
+        match self.handlers.get_mut(&message.channel) {
+            Some(handler) => handler(context, message),
+            None => {
+                tracing::debug!("No handler for custom channel {:?}", message.channel);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_custom_handlers() {
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    let received = Arc::new(Mutex::new(vec![]));
+    let mut handlers = CustomHandlers::<u32>::default();
+    handlers.insert("numbers".to_owned(), {
+        let received = received.clone();
+        move |sender, value: Vec<i64>| received.lock().push((sender, value))
+    });
+
+    handlers.handle(7, &CustomMessage::new("numbers", &vec![1_i64, -2]).unwrap());
+    handlers.handle(7, &CustomMessage::new("other", &vec![3_i64]).unwrap());
+    handlers.handle(7, &CustomMessage::new("numbers", &true).unwrap());
+
+    assert_eq!(*received.lock(), vec![(7, vec![1, -2])]);
+}
@@ -0,0 +1,237 @@
+//! Validation of the untrusted input we receive from clients.
+
+use crate::messages::InputDelta;
+use std::ops::RangeInclusive;
+
+/// What to do when a client sends input that violates the [`InputLimits`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ViolationPolicy {
+    /// Fix the input (clamp values, drop bad events) and use it.
+    #[default]
+    Clamp,
+
+    /// Discard the whole input message.
+    Ignore,
+
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// Limits on the input the server accepts from clients.
+///
+/// Violations are logged, and then handled according to [`Self::policy`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputLimits {
+    /// Largest allowed screen size, in points.
+    pub max_screen_size: egui::Vec2,
+
+    /// Allowed range of `pixels_per_point`.
+    pub pixels_per_point: RangeInclusive<f32>,
+
+    /// Allowed range of `max_texture_side`.
+    pub max_texture_side: RangeInclusive<usize>,
+
+    /// Maximum number of events in one input message,
+    /// and in all the input waiting for the next frame.
+    pub max_events: usize,
+
+    /// Maximum number of hovered files in one input message.
+    pub max_files: usize,
+
+    /// Maximum size of an uploaded (dropped) file, in bytes.
+    pub max_dropped_file_size: usize,
+
+    /// Maximum size of pasted, typed or composed (IME) text in one event, in bytes.
+    pub max_paste_size: usize,
+
+    /// What to do when the limits are violated.
+    pub policy: ViolationPolicy,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        Self {
+            max_screen_size: egui::vec2(16_384.0, 16_384.0),
+            pixels_per_point: 0.25..=8.0,
+            max_texture_side: 256..=16_384,
+            max_events: 10_000,
+            max_files: 100,
+            max_dropped_file_size: 16 * 1024 * 1024,
+            max_paste_size: crate::clipboard::DEFAULT_MAX_CLIPBOARD_SIZE,
+            policy: ViolationPolicy::Clamp,
+        }
+    }
+}
+
+impl InputLimits {
+    /// Check the input against the limits, clamping it to them.
+    ///
+    /// Returns a description of each violation.
+    pub fn sanitize(&self, input: &mut InputDelta) -> Vec<String> {
+        let InputDelta {
+            events,
+            screen_rect,
+            pixels_per_point,
+            max_texture_side,
+            modifiers: _,
+            has_focus: _,
+            hovered_files,
+        } = input;
+
+        let mut violations = vec![];
+
+        if let Some(rect) = *screen_rect {
+            if !rect.is_finite() {
+                violations.push(format!("Non-finite screen_rect {:?}", rect));
+                *screen_rect = None;
+            } else {
+                let max = self.max_screen_size;
+                let mut rect = rect;
+                if rect.width() < 0.0
+                    || rect.height() < 0.0
+                    || rect.width() > max.x
+                    || rect.height() > max.y
+                {
+                    violations.push(format!("Bad screen size {:?}", rect.size()));
+                    let size = rect.size().max(egui::Vec2::ZERO).min(max);
+                    rect = egui::Rect::from_min_size(rect.min, size);
+                }
+                if rect.min.x.abs() > max.x || rect.min.y.abs() > max.y {
+                    violations.push(format!("Bad screen position {:?}", rect.min));
+                    let min = rect.min.clamp((-max).to_pos2(), max.to_pos2());
+                    rect = egui::Rect::from_min_size(min, rect.size());
+                }
+                *screen_rect = Some(rect);
+            }
+        }
+
+        if let Some(ppp) = *pixels_per_point {
+            if !ppp.is_finite() {
+                violations.push(format!("Non-finite pixels_per_point {}", ppp));
+                *pixels_per_point = None;
+            } else if !self.pixels_per_point.contains(&ppp) {
+                violations.push(format!("Bad pixels_per_point {}", ppp));
+                *pixels_per_point = Some(
+                    ppp.max(*self.pixels_per_point.start())
+                        .min(*self.pixels_per_point.end()),
+                );
+            }
+        }
+
+        if let Some(side) = *max_texture_side {
+            if !self.max_texture_side.contains(&side) {
+                violations.push(format!("Bad max_texture_side {}", side));
+                *max_texture_side = Some(
+                    side.max(*self.max_texture_side.start())
+                        .min(*self.max_texture_side.end()),
+                );
+            }
+        }
+
+        if events.len() > self.max_events {
+            violations.push(format!("Too many events: {}", events.len()));
+            events.truncate(self.max_events);
+        }
+
+        let num_events = events.len();
+        events.retain(is_event_finite);
+        if events.len() != num_events {
+            violations.push(format!(
+                "{} events with non-finite values",
+                num_events - events.len()
+            ));
+        }
+
+        let max_paste_size = self.max_paste_size;
+        events.retain(|event| match event {
+            egui::Event::Paste(text)
+            | egui::Event::Text(text)
+            | egui::Event::CompositionUpdate(text)
+            | egui::Event::CompositionEnd(text)
+                if text.len() > max_paste_size =>
+            {
+                violations.push(format!(
+                    "Text is too large: {:.1} MB",
+                    text.len() as f32 * 1e-6
+                ));
+                false
+            }
+            _ => true,
+        });
+
+        if let Some(hovered) = hovered_files {
+            if hovered.len() > self.max_files {
+                violations.push(format!("Too many hovered files: {}", hovered.len()));
+                hovered.truncate(self.max_files);
+            }
+        }
+
+        violations
+    }
+}
+
+fn is_event_finite(event: &egui::Event) -> bool {
+    match event {
+        egui::Event::PointerMoved(pos) | egui::Event::PointerButton { pos, .. } => pos.is_finite(),
+        egui::Event::Scroll(delta) => delta.is_finite(),
+        egui::Event::Zoom(factor) => factor.is_finite(),
+        egui::Event::Touch { pos, force, .. } => pos.is_finite() && force.is_finite(),
+        _ => true,
+    }
+}
+
+#[test]
+fn test_sanitize() {
+    let limits = InputLimits::default();
+
+    let mut input = InputDelta {
+        screen_rect: Some(egui::Rect::from_min_size(
+            egui::Pos2::ZERO,
+            egui::vec2(1e9, 600.0),
+        )),
+        pixels_per_point: Some(f32::NAN),
+        events: vec![
+            egui::Event::PointerMoved(egui::pos2(f32::INFINITY, 0.0)),
+            egui::Event::Text("ok".to_owned()),
+            egui::Event::Paste("x".repeat(limits.max_paste_size + 1)),
+            egui::Event::Text("x".repeat(limits.max_paste_size + 1)),
+        ],
+        ..Default::default()
+    };
+
+    let violations = limits.sanitize(&mut input);
+    assert_eq!(violations.len(), 5);
+    assert_eq!(
+        input,
+        InputDelta {
+            screen_rect: Some(egui::Rect::from_min_size(
+                egui::Pos2::ZERO,
+                egui::vec2(limits.max_screen_size.x, 600.0),
+            )),
+            events: vec![egui::Event::Text("ok".to_owned())],
+            ..Default::default()
+        }
+    );
+
+    let mut far_away = InputDelta {
+        screen_rect: Some(egui::Rect::from_min_size(
+            egui::pos2(-1e6, 0.0),
+            egui::vec2(800.0, 600.0),
+        )),
+        ..Default::default()
+    };
+    assert_eq!(limits.sanitize(&mut far_away).len(), 1);
+    assert_eq!(
+        far_away.screen_rect,
+        Some(egui::Rect::from_min_size(
+            egui::pos2(-limits.max_screen_size.x, 0.0),
+            egui::vec2(800.0, 600.0),
+        ))
+    );
+
+    let mut good_input = InputDelta {
+        pixels_per_point: Some(2.0),
+        ..Default::default()
+    };
+    assert!(limits.sanitize(&mut good_input).is_empty());
+}
@@ -70,19 +70,46 @@
 #![allow(clippy::float_cmp)]
 #![allow(clippy::manual_range_contains)]
 
+mod access_list;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod audit;
+pub mod capabilities;
 mod client;
+pub mod clipboard;
+pub mod custom;
+mod input_limits;
 pub mod messages;
+pub mod notifications;
+pub mod open_url;
+mod presence;
+mod rate_limit;
+mod role;
 mod server;
+mod textures;
+pub mod transfer;
+pub use access_list::{AccessList, SharedAccessList};
+pub use audit::AuditLog;
+pub use capabilities::Capabilities;
 pub use client::Client;
+pub use clipboard::{Clipboard, MemoryClipboard};
+pub use custom::CustomMessage;
 use egui::PlatformOutput;
+pub use input_limits::{InputLimits, ViolationPolicy};
 use messages::ClippedNetMesh;
-pub use server::{ClientId, Server, DEFAULT_MAX_UPDATE_INTERVAL, DEFAULT_MIN_UPDATE_INTERVAL};
+pub use notifications::Notification;
+pub use open_url::{OpenUrlPolicy, UrlAllowlist};
+pub use rate_limit::RateLimit;
+pub use role::Role;
+pub use server::{
+    ClientId, InputMode, Server, DEFAULT_MAX_UPDATE_INTERVAL, DEFAULT_MIN_UPDATE_INTERVAL,
+};
 use std::sync::Arc;
 
 /// All TCP packets are prefixed with this.
 ///
 /// b"eterm", major, minor, patch
-pub(crate) const PROTOCOL_HEADER: [u8; 8] = [b'e', b't', b'e', b'r', b'm', 0, 0, 1];
+pub(crate) const PROTOCOL_HEADER: [u8; 8] = [b'e', b't', b'e', b'r', b'm', 0, 0, 2];
 
 #[test]
 fn test_version() {
@@ -111,14 +138,145 @@ pub struct EtermFrame {
     pub textures_delta: egui::TexturesDelta,
 }
 
+impl EtermFrame {
+    /// Replace this frame with a newer one, as if both had been painted in order.
+    ///
+    /// The texture updates and platform output (copied text, opened urls, …)
+    /// of the skipped frame are merged into the newer one.
+    pub fn append(&mut self, newer: EtermFrame) {
+        let EtermFrame {
+            frame_index,
+            platform_output,
+            clipped_net_mesh,
+            textures_delta,
+        } = newer;
+
+        self.frame_index = frame_index;
+        self.platform_output.append(platform_output);
+        self.clipped_net_mesh = clipped_net_mesh;
+        append_textures_delta(&mut self.textures_delta, textures_delta);
+    }
+}
+
+/// Like [`egui::TexturesDelta::append`], but drops updates made obsolete by the newer delta.
+fn append_textures_delta(older: &mut egui::TexturesDelta, newer: egui::TexturesDelta) {
+    let egui::TexturesDelta { set, free } = newer;
+
+    // A freed texture needs no updates:
+    older.set.retain(|(id, _)| !free.contains(id));
+
+    for (id, delta) in set {
+        if delta.is_whole() {
+            // A full update replaces all earlier updates of the same texture,
+            // and (re)creates it if it was freed (frees are applied last):
+            older.set.retain(|(older_id, _)| *older_id != id);
+            older.free.retain(|older_id| *older_id != id);
+        }
+        older.set.push((id, delta));
+    }
+
+    older.free.extend(free);
+}
+
+#[test]
+fn test_append_textures_delta() {
+    use egui::{epaint::ImageDelta, Color32, ColorImage, TextureId};
+
+    let image = || ColorImage::new([2, 2], Color32::WHITE);
+    let whole = || ImageDelta::full(image(), Default::default());
+    let partial = || ImageDelta::partial([0, 0], image(), Default::default());
+
+    let a = TextureId::Managed(1);
+    let b = TextureId::Managed(2);
+    let c = TextureId::Managed(3);
+    let user = TextureId::User(4);
+
+    let mut merged = egui::TexturesDelta {
+        set: vec![(a, whole()), (b, whole())],
+        free: vec![],
+    };
+    append_textures_delta(
+        &mut merged,
+        egui::TexturesDelta {
+            set: vec![(a, partial()), (c, whole())],
+            free: vec![],
+        },
+    );
+    append_textures_delta(
+        &mut merged,
+        egui::TexturesDelta {
+            set: vec![(a, whole())],
+            free: vec![b],
+        },
+    );
+
+    let set: Vec<(TextureId, bool)> = merged
+        .set
+        .iter()
+        .map(|(id, delta)| (*id, delta.is_whole()))
+        .collect();
+    assert_eq!(set, vec![(c, true), (a, true)]);
+    assert_eq!(merged.free, vec![b]);
+
+    // A texture that is freed and then set again must survive:
+    let mut merged = egui::TexturesDelta {
+        set: vec![],
+        free: vec![user, b],
+    };
+    append_textures_delta(
+        &mut merged,
+        egui::TexturesDelta {
+            set: vec![(user, whole())],
+            free: vec![],
+        },
+    );
+    assert_eq!(merged.set.len(), 1);
+    assert_eq!(merged.set[0].0, user);
+    assert_eq!(merged.free, vec![b]);
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub enum ClientToServerMessage {
     Input {
-        raw_input: egui::RawInput,
+        /// What changed since the last input on this connection.
+        input: messages::InputDelta,
         /// Seconds since epoch. Used to measure latency.
         client_time: f64,
     },
     Goodbye,
+
+    /// Watch the session of another client instead of using our own,
+    /// or go back to our own session with `None`.
+    ///
+    /// Input sent while spectating is ignored, except for the screen size.
+    Spectate {
+        client_id: Option<ClientId>,
+    },
+
+    /// Give control of our session to one of its spectators.
+    ///
+    /// We become a spectator of our old session.
+    HandOff {
+        to: ClientId,
+    },
+
+    /// Who we are and what we can do.
+    ///
+    /// Sent first thing on a new connection.
+    Hello(Capabilities),
+
+    /// What to call us when showing our pointer to others.
+    ///
+    /// Sent first thing on a new connection.
+    SetName {
+        name: String,
+    },
+
+    /// Application-defined, see [`Client::send_custom`].
+    Custom(CustomMessage),
+
+    /// Part of an upload, or the cancellation of a download.
+    Transfer(transfer::TransferMessage),
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -131,13 +289,73 @@ pub enum ServerToClientMessage {
         client_time: Option<f64>,
         textures_delta: egui::TexturesDelta,
     },
+
+    /// Sent on connect, and whenever the connection's session or spectators change.
+    SessionInfo(SessionInfo),
+
+    /// Application-defined, see [`Server::send_custom`].
+    Custom(CustomMessage),
+
+    /// Part of a download, or the cancellation of an upload.
+    Transfer(transfer::TransferMessage),
+}
+
+/// Which session a connection is showing, and who else is watching it.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SessionInfo {
+    /// The session this connection controls (even while it is spectating another one).
+    pub client_id: ClientId,
+
+    /// If set, we are a spectator of this session and our input is ignored.
+    pub spectating: Option<ClientId>,
+
+    /// The connections watching the session we control.
+    ///
+    /// Use their ids with [`Client::hand_off`].
+    pub spectators: Vec<ClientId>,
+}
+
+/// Largest message a client may send, after decompression.
+///
+/// Kept small, since the server decodes the messages of all its clients.
+pub const MAX_CLIENT_MESSAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Largest message a server may send (e.g. a frame), after decompression.
+pub const MAX_SERVER_MESSAGE_SIZE: u64 = 128 * 1024 * 1024;
+
+/// A message that goes over the eterm connection in one direction.
+pub trait Message: serde::Serialize + serde::de::DeserializeOwned {
+    /// Largest allowed message, after decompression.
+    const MAX_SIZE: u64;
+}
+
+impl Message for ClientToServerMessage {
+    const MAX_SIZE: u64 = MAX_CLIENT_MESSAGE_SIZE;
 }
 
-fn encode_message<M: ?Sized + serde::Serialize>(message: &M) -> anyhow::Result<Packet> {
+impl Message for ServerToClientMessage {
+    const MAX_SIZE: u64 = MAX_SERVER_MESSAGE_SIZE;
+}
+
+/// Limits the memory used by the zstd decoder (2^23 = 8 MB window).
+const ZSTD_WINDOW_LOG_MAX: u32 = 23;
+
+fn bincode_options(max_size: u64) -> impl bincode::Options {
+    use bincode::Options as _;
+    bincode::options().with_limit(max_size)
+}
+
+/// Serialize and compress a message for sending.
+///
+/// # Errors
+/// If the message is larger than [`Message::MAX_SIZE`].
+pub fn encode_message<M: Message>(message: &M) -> anyhow::Result<Packet> {
     use anyhow::Context as _;
     use bincode::Options as _;
 
-    let bincoded = bincode::options().serialize(message).context("bincode")?;
+    let bincoded = bincode_options(M::MAX_SIZE)
+        .serialize(message)
+        .context("bincode")?;
 
     const ZSTD_LEVEL: i32 = 5;
     let compressed =
@@ -146,17 +364,157 @@ fn encode_message<M: ?Sized + serde::Serialize>(message: &M) -> anyhow::Result<P
     Ok(compressed.into())
 }
 
-fn decode_message<M: serde::de::DeserializeOwned>(packet: &[u8]) -> anyhow::Result<M> {
-    use anyhow::Context as _;
-    use bincode::Options as _;
+/// Why a packet could not be decoded with [`decode_message`].
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The packet is not valid zstd.
+    Decompress(std::io::Error),
+
+    /// The packet decompresses to more than [`Message::MAX_SIZE`] bytes.
+    TooLarge { max_size: u64 },
+
+    /// The decompressed packet is not a valid message.
+    Deserialize(bincode::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decompress(_) => write!(f, "zstd"),
+            Self::TooLarge { max_size } => write!(
+                f,
+                "message decompresses to more than {:.1} MB",
+                *max_size as f32 * 1e-6
+            ),
+            Self::Deserialize(_) => write!(f, "bincode"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decompress(err) => Some(err),
+            Self::TooLarge { .. } => None,
+            Self::Deserialize(err) => Some(err),
+        }
+    }
+}
+
+/// Decompress and deserialize a received packet.
+///
+/// Packets come from the network, so this never panics,
+/// and never uses more than about [`Message::MAX_SIZE`] of memory.
+///
+/// # Errors
+/// If the packet is malformed or too large.
+pub fn decode_message<M: Message>(packet: &[u8]) -> Result<M, DecodeError> {
+    decode_message_with_limit(packet, M::MAX_SIZE)
+}
 
-    let bincoded = zstd::decode_all(packet).context("zstd")?;
+/// Like [`decode_message`], but with a custom size limit.
+fn decode_message_with_limit<M: serde::de::DeserializeOwned>(
+    packet: &[u8],
+    max_size: u64,
+) -> Result<M, DecodeError> {
+    use bincode::Options as _;
+    use std::io::Read as _;
+
+    let mut decoder = zstd::stream::read::Decoder::new(packet).map_err(DecodeError::Decompress)?;
+    decoder
+        .window_log_max(ZSTD_WINDOW_LOG_MAX)
+        .map_err(DecodeError::Decompress)?;
+
+    let mut bincoded = vec![];
+    decoder
+        .take(max_size + 1)
+        .read_to_end(&mut bincoded)
+        .map_err(DecodeError::Decompress)?;
+    if bincoded.len() as u64 > max_size {
+        return Err(DecodeError::TooLarge { max_size });
+    }
 
-    let message = bincode::options()
+    bincode_options(max_size)
         .deserialize(&bincoded)
-        .context("bincode")?;
+        .map_err(DecodeError::Deserialize)
+}
+
+#[test]
+fn test_decode_errors() {
+    let message = ClientToServerMessage::Goodbye;
+    let packet = encode_message(&message).unwrap();
+    assert!(matches!(
+        decode_message::<ClientToServerMessage>(&packet),
+        Ok(ClientToServerMessage::Goodbye)
+    ));
+
+    assert!(matches!(
+        decode_message::<ClientToServerMessage>(b"not zstd"),
+        Err(DecodeError::Decompress(_))
+    ));
+
+    let garbage = zstd::encode_all(&[0xff_u8; 100][..], 0).unwrap();
+    assert!(matches!(
+        decode_message::<ClientToServerMessage>(&garbage),
+        Err(DecodeError::Deserialize(_))
+    ));
+
+    // A zstd bomb (with a small limit, to keep the test light):
+    let max_size = 64 * 1024;
+    let zeros = vec![0_u8; max_size as usize + 1];
+    let bomb = zstd::encode_all(&zeros[..], 3).unwrap();
+    assert!(bomb.len() < 1_000);
+    assert!(matches!(
+        decode_message_with_limit::<ClientToServerMessage>(&bomb, max_size),
+        Err(DecodeError::TooLarge { .. })
+    ));
+}
 
-    Ok(message)
+#[test]
+fn test_message_size_limits() {
+    let big = CustomMessage {
+        channel: "big".to_owned(),
+        payload: vec![0; MAX_CLIENT_MESSAGE_SIZE as usize + 1],
+    };
+
+    assert!(encode_message(&ClientToServerMessage::Custom(big.clone())).is_err());
+
+    let packet = encode_message(&ServerToClientMessage::Custom(big)).unwrap();
+    assert!(matches!(
+        decode_message::<ServerToClientMessage>(&packet),
+        Ok(ServerToClientMessage::Custom(_))
+    ));
+    assert!(matches!(
+        decode_message::<ClientToServerMessage>(&packet),
+        Err(DecodeError::TooLarge {
+            max_size: MAX_CLIENT_MESSAGE_SIZE
+        })
+    ));
+}
+
+/// Input waiting to be sent.
+///
+/// It is delta-encoded into a [`ClientToServerMessage::Input`] by the connection
+/// that sends it, since the delta depends on what that connection has already sent.
+pub(crate) struct OutgoingInput {
+    pub raw_input: egui::RawInput,
+    /// Seconds since epoch.
+    pub client_time: f64,
+}
+
+impl OutgoingInput {
+    /// Returns `None` if there is nothing new to send.
+    pub(crate) fn encode(self, input_state: &mut egui::RawInput) -> Option<ClientToServerMessage> {
+        let input = messages::InputDelta::encode(input_state, self.raw_input);
+        if input.is_empty() {
+            None
+        } else {
+            Some(ClientToServerMessage::Input {
+                input,
+                client_time: self.client_time,
+            })
+        }
+    }
 }
 
 /// Show full cause chain in a single line
@@ -171,90 +529,107 @@ pub(crate) fn error_display_chain(error: &dyn std::error::Error) -> String {
 
 // ----------------------------------------------------------------------------
 
+/// All packets are prefixed by [`PROTOCOL_HEADER`] and a u32 (LE) length.
+pub(crate) const PACKET_HEADER_LEN: usize = PROTOCOL_HEADER.len() + 4;
+
+/// Refuse packets larger than this.
+const MAX_PACKET_SIZE: usize = 32_000_000;
+
+/// We read at least this much from the socket at a time.
+const MIN_READ_SIZE: usize = 64 * 1024;
+
 /// Wrapper around a non-blocking [`std::net::TcpStream`].
 pub(crate) struct TcpEndpoint {
     tcp_stream: std::net::TcpStream,
+
+    /// Reusable receive buffer. Only `recv_buffer[recv_start..recv_end]` contains unread data.
+    recv_buffer: Vec<u8>,
+    recv_start: usize,
+    recv_end: usize,
+
+    /// Length of the payload of the packet at `recv_start`, once its header has been parsed.
+    packet_len: Option<usize>,
+
+    /// Bytes of the packet we last handed out, to be consumed on the next read.
+    consume_on_next_read: usize,
 }
 
 impl TcpEndpoint {
-    /// returns immediately if there is nothing to read
-    fn try_receive_packet(&mut self) -> anyhow::Result<Option<Packet>> {
-        use std::io::Read as _;
-
-        // All messages are length-prefixed by PROTOCOL_HEADER and u32 (LE).
-        let mut header = [0_u8; 12];
-        match self.tcp_stream.peek(&mut header) {
-            Ok(12) => {}
-            Ok(_) => {
-                return Ok(None);
-            }
-            Err(err) => {
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    return Ok(None);
-                } else {
-                    return Err(err.into());
-                }
-            }
+    pub(crate) fn new(tcp_stream: std::net::TcpStream) -> Self {
+        Self {
+            tcp_stream,
+            recv_buffer: Default::default(),
+            recv_start: 0,
+            recv_end: 0,
+            packet_len: None,
+            consume_on_next_read: 0,
         }
+    }
 
-        let protocol = &header[..PROTOCOL_HEADER.len()];
-        let length = &header[PROTOCOL_HEADER.len()..];
-        let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    /// returns immediately if there is nothing to read.
+    ///
+    /// The returned packet borrows the internal receive buffer,
+    /// and is valid until the next call.
+    fn try_receive_packet(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        use std::io::Read as _;
 
-        if protocol[0..5] != PROTOCOL_HEADER[0..5] {
-            anyhow::bail!("The other side is not eterm");
-        }
+        self.recv_start += std::mem::take(&mut self.consume_on_next_read);
 
-        if protocol != PROTOCOL_HEADER {
-            anyhow::bail!(
-                "This side uses eterm {}.{}.{}, the other side is on {}.{}.{}",
-                PROTOCOL_HEADER[5],
-                PROTOCOL_HEADER[6],
-                PROTOCOL_HEADER[7],
-                protocol[5],
-                protocol[6],
-                protocol[7],
-            );
+        if self.recv_start == self.recv_end {
+            self.recv_start = 0;
+            self.recv_end = 0;
+            // Don't hold on to the memory of a large packet:
+            if self.recv_buffer.len() > MIN_READ_SIZE {
+                self.recv_buffer.truncate(MIN_READ_SIZE);
+                self.recv_buffer.shrink_to_fit();
+            }
         }
 
-        if length > 32_000_000 {
-            anyhow::bail!("Refusing packet of {:.1} MB", length as f32 * 1e-6);
-        }
+        loop {
+            let available = self.recv_end - self.recv_start;
 
-        // See if we have the whole packet yet:
-        let mut length_and_packet = vec![0_u8; header.len() + length];
-        match self.tcp_stream.peek(&mut length_and_packet) {
-            Ok(bytes_read) => {
-                if bytes_read != length_and_packet.len() {
-                    return Ok(None); // not yet!
-                }
-            }
-            Err(err) => {
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    return Ok(None);
-                } else {
-                    return Err(err.into());
-                }
+            if self.packet_len.is_none() && available >= PACKET_HEADER_LEN {
+                let header =
+                    &self.recv_buffer[self.recv_start..self.recv_start + PACKET_HEADER_LEN];
+                self.packet_len = Some(parse_packet_header(header)?);
             }
-        }
 
-        // consume the bytes:
-        self.tcp_stream.read_exact(&mut length_and_packet)?;
+            let needed = PACKET_HEADER_LEN + self.packet_len.unwrap_or(0);
 
-        let packet = &length_and_packet[header.len()..];
+            if self.packet_len.is_some() && available >= needed {
+                let packet_start = self.recv_start + PACKET_HEADER_LEN;
+                let packet_end = self.recv_start + needed;
+                self.packet_len = None;
+                self.consume_on_next_read = needed;
+                return Ok(Some(&self.recv_buffer[packet_start..packet_end]));
+            }
 
-        Ok(Some(packet.into()))
-    }
+            // We need more data. Make sure the whole packet fits in the buffer:
+            if self.recv_buffer.len() - self.recv_start < needed.max(MIN_READ_SIZE) {
+                // Move the partial packet to the front of the buffer:
+                self.recv_buffer
+                    .copy_within(self.recv_start..self.recv_end, 0);
+                self.recv_end -= self.recv_start;
+                self.recv_start = 0;
+                let new_len = needed.max(self.recv_end + MIN_READ_SIZE);
+                if self.recv_buffer.len() < new_len {
+                    self.recv_buffer.resize(new_len, 0);
+                }
+            }
 
-    /// returns immediately if there is nothing to read
-    fn try_receive_message<M: serde::de::DeserializeOwned>(&mut self) -> anyhow::Result<Option<M>> {
-        use anyhow::Context as _;
-        match self.try_receive_packet().context("receive")? {
-            Some(packet) => {
-                let message = crate::decode_message(&packet).context("decode")?;
-                Ok(Some(message))
+            match self.tcp_stream.read(&mut self.recv_buffer[self.recv_end..]) {
+                Ok(0) => {
+                    anyhow::bail!("Connection closed by the other side");
+                }
+                Ok(bytes_read) => {
+                    self.recv_end += bytes_read;
+                }
+                Err(err) => match err.kind() {
+                    std::io::ErrorKind::WouldBlock => return Ok(None),
+                    std::io::ErrorKind::Interrupted => {}
+                    _ => return Err(err.into()),
+                },
             }
-            None => Ok(None),
         }
     }
 
@@ -286,7 +661,80 @@ impl TcpEndpoint {
         }
     }
 
-    fn send_message<M: serde::Serialize>(&mut self, message: &M) -> anyhow::Result<()> {
+    fn send_message<M: Message>(&mut self, message: &M) -> anyhow::Result<()> {
         self.send_packet(&encode_message(message)?)
     }
 }
+
+/// Validates a packet header, and returns the length of the packet that follows it.
+pub(crate) fn parse_packet_header(header: &[u8]) -> anyhow::Result<usize> {
+    let protocol = &header[..PROTOCOL_HEADER.len()];
+    let length = &header[PROTOCOL_HEADER.len()..PACKET_HEADER_LEN];
+    let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+
+    if protocol[0..5] != PROTOCOL_HEADER[0..5] {
+        anyhow::bail!("The other side is not eterm");
+    }
+
+    if protocol != PROTOCOL_HEADER {
+        anyhow::bail!(
+            "This side uses eterm {}.{}.{}, the other side is on {}.{}.{}",
+            PROTOCOL_HEADER[5],
+            PROTOCOL_HEADER[6],
+            PROTOCOL_HEADER[7],
+            protocol[5],
+            protocol[6],
+            protocol[7],
+        );
+    }
+
+    if length > MAX_PACKET_SIZE {
+        anyhow::bail!("Refusing packet of {:.1} MB", length as f32 * 1e-6);
+    }
+
+    Ok(length)
+}
+
+#[test]
+fn test_receive_packets_in_pieces() {
+    use std::io::Write as _;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut sender = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (receiver, _) = listener.accept().unwrap();
+    receiver.set_nonblocking(true).unwrap();
+    let mut endpoint = TcpEndpoint::new(receiver);
+
+    let small = vec![1_u8; 10];
+    let large = vec![2_u8; 3 * MIN_READ_SIZE + 17];
+
+    let mut stream = vec![];
+    for packet in [&small, &large, &small] {
+        stream.extend_from_slice(&PROTOCOL_HEADER);
+        stream.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        stream.extend_from_slice(packet);
+    }
+
+    let mut received = vec![];
+    for chunk in stream.chunks(5000) {
+        sender.write_all(chunk).unwrap();
+        sender.flush().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        while let Some(packet) = endpoint.try_receive_packet().unwrap() {
+            received.push(packet.to_vec());
+        }
+    }
+
+    let start = std::time::Instant::now();
+    while received.len() < 3 && start.elapsed().as_secs() < 5 {
+        while let Some(packet) = endpoint.try_receive_packet().unwrap() {
+            received.push(packet.to_vec());
+        }
+    }
+
+    assert_eq!(received, vec![small.clone(), large, small]);
+
+    // Once drained, the buffer shrinks back:
+    assert!(endpoint.try_receive_packet().unwrap().is_none());
+    assert!(endpoint.recv_buffer.capacity() <= MIN_READ_SIZE);
+}
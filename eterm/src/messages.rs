@@ -1,6 +1,6 @@
 use egui::{
     epaint::{self, Color32, Pos2, Primitive, Rect, TextureId},
-    ClippedPrimitive,
+    ClippedPrimitive, RawInput,
 };
 use serde::{Deserialize, Serialize};
 
@@ -11,6 +11,19 @@ pub struct ClippedNetMesh {
     pub mesh: NetMesh,
 }
 
+impl ClippedNetMesh {
+    /// Scale everything towards the top left corner, e.g. to fit a smaller screen.
+    pub fn scale(&mut self, factor: f32) {
+        self.clip_rect = Rect::from_min_max(
+            (self.clip_rect.min.to_vec2() * factor).to_pos2(),
+            (self.clip_rect.max.to_vec2() * factor).to_pos2(),
+        );
+        for pos in &mut self.mesh.pos {
+            *pos = (pos.to_vec2() * factor).to_pos2();
+        }
+    }
+}
+
 pub fn into_clipped_net_meshes(primitives: Vec<ClippedPrimitive>) -> Vec<ClippedNetMesh> {
     primitives
         .into_iter()
@@ -62,3 +75,172 @@ impl From<&NetMesh> for epaint::Mesh {
         }
     }
 }
+
+// ----------------------------------------------------------------------------
+
+/// Compact version of [`egui::RawInput`] for sending over the network.
+///
+/// Only contains the new events, plus the parts of the input state
+/// (screen size, scale, focus, …) that changed since the last [`InputDelta`].
+/// The receiving side rebuilds the full [`egui::RawInput`] with [`Self::decode`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputDelta {
+    pub events: Vec<egui::Event>,
+
+    /// `None` means unchanged.
+    pub screen_rect: Option<Rect>,
+    pub pixels_per_point: Option<f32>,
+    pub max_texture_side: Option<usize>,
+    pub modifiers: Option<egui::Modifiers>,
+    pub has_focus: Option<bool>,
+    pub hovered_files: Option<Vec<egui::HoveredFile>>,
+}
+
+impl InputDelta {
+    /// What is new in `raw_input` compared to `state`,
+    /// which is then updated to include `raw_input`.
+    ///
+    /// `raw_input.time` and `raw_input.predicted_dt` are ignored,
+    /// and so is `raw_input.dropped_files`: files are uploaded separately
+    /// (see [`crate::Client::upload`]).
+    /// Start with `state = RawInput::default()` for each new connection.
+    pub fn encode(state: &mut RawInput, raw_input: RawInput) -> Self {
+        let RawInput {
+            screen_rect,
+            pixels_per_point,
+            max_texture_side,
+            time: _,
+            predicted_dt: _,
+            modifiers,
+            events,
+            hovered_files,
+            dropped_files: _,
+            has_focus,
+        } = raw_input;
+
+        fn changed<T: PartialEq + Clone>(state: &mut T, new: T) -> Option<T> {
+            if *state == new {
+                None
+            } else {
+                *state = new.clone();
+                Some(new)
+            }
+        }
+
+        // `None` in `RawInput` already means "unchanged":
+        fn changed_option<T: PartialEq + Clone>(
+            state: &mut Option<T>,
+            new: Option<T>,
+        ) -> Option<T> {
+            let new = new?;
+            changed(state, Some(new.clone())).map(|_| new)
+        }
+
+        Self {
+            events,
+            screen_rect: changed_option(&mut state.screen_rect, screen_rect),
+            pixels_per_point: changed_option(&mut state.pixels_per_point, pixels_per_point),
+            max_texture_side: changed_option(&mut state.max_texture_side, max_texture_side),
+            modifiers: changed(&mut state.modifiers, modifiers),
+            has_focus: changed(&mut state.has_focus, has_focus),
+            hovered_files: changed(&mut state.hovered_files, hovered_files),
+        }
+    }
+
+    /// Rebuild the [`RawInput`] this was encoded from.
+    ///
+    /// `state` is the input state before this delta, and is updated with it.
+    /// Start with `state = RawInput::default()` for each new connection.
+    pub fn decode(self, state: &mut RawInput) -> RawInput {
+        let Self {
+            events,
+            screen_rect,
+            pixels_per_point,
+            max_texture_side,
+            modifiers,
+            has_focus,
+            hovered_files,
+        } = self;
+
+        if screen_rect.is_some() {
+            state.screen_rect = screen_rect;
+        }
+        if pixels_per_point.is_some() {
+            state.pixels_per_point = pixels_per_point;
+        }
+        if max_texture_side.is_some() {
+            state.max_texture_side = max_texture_side;
+        }
+        if let Some(modifiers) = modifiers {
+            state.modifiers = modifiers;
+        }
+        if let Some(has_focus) = has_focus {
+            state.has_focus = has_focus;
+        }
+        if let Some(hovered_files) = hovered_files {
+            state.hovered_files = hovered_files;
+        }
+
+        RawInput {
+            events,
+            ..state.clone()
+        }
+    }
+
+    /// Nothing new?
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+#[test]
+fn test_input_delta() {
+    let mut encoder_state = RawInput::default();
+    let mut decoder_state = RawInput::default();
+
+    let screen_rect = Rect::from_min_size(Pos2::ZERO, egui::vec2(800.0, 600.0));
+    let first = RawInput {
+        screen_rect: Some(screen_rect),
+        pixels_per_point: Some(2.0),
+        time: Some(1.0),
+        events: vec![egui::Event::Text("a".to_owned())],
+        ..Default::default()
+    };
+
+    let delta = InputDelta::encode(&mut encoder_state, first.clone());
+    assert_eq!(delta.screen_rect, Some(screen_rect));
+    let decoded = delta.decode(&mut decoder_state);
+    assert_eq!(
+        decoded,
+        RawInput {
+            time: None,
+            ..first.clone()
+        }
+    );
+
+    // Only the time changed, so nothing to send:
+    let second = RawInput {
+        time: Some(2.0),
+        events: vec![],
+        ..first.clone()
+    };
+    assert!(InputDelta::encode(&mut encoder_state, second).is_empty());
+
+    // Focus changed:
+    let third = RawInput {
+        has_focus: false,
+        events: vec![],
+        ..first
+    };
+    let delta = InputDelta::encode(&mut encoder_state, third);
+    assert_eq!(
+        delta,
+        InputDelta {
+            has_focus: Some(false),
+            ..Default::default()
+        }
+    );
+    let decoded = delta.decode(&mut decoder_state);
+    assert_eq!(decoded.screen_rect, Some(screen_rect));
+    assert!(!decoded.has_focus);
+}
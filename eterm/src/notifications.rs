@@ -0,0 +1,263 @@
+//! Notifications the server shows on top of the ui of its clients.
+//!
+//! ``` no_run
+//! use eterm::notifications::{Notification, Target};
+//! use std::time::Duration;
+//!
+//! let mut server = eterm::Server::new("0.0.0.0:8505")?;
+//! server.notify(
+//!     Target::All,
+//!     Notification::warning("Deploy starting, service restarts in 60s")
+//!         .with_duration(Duration::from_secs(60)),
+//! );
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use crate::ClientId;
+use std::time::{Duration, Instant};
+
+/// Who to show a [`Notification`] to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// One client (or the shared session, if that is its id).
+    Client(ClientId),
+
+    /// Everyone connected.
+    All,
+}
+
+impl Target {
+    pub(crate) fn includes(self, client_id: ClientId) -> bool {
+        match self {
+            Self::Client(target) => target == client_id,
+            Self::All => true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+}
+
+/// A message shown in the top right corner, on top of the ui.
+///
+/// Spectators see the notifications of the session they watch, but only its driver can dismiss them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    pub text: String,
+    pub severity: Severity,
+
+    /// Remove it after this long.
+    /// If `None`, it stays until the user dismisses it.
+    pub duration: Option<Duration>,
+}
+
+impl Notification {
+    /// Shown until dismissed.
+    pub fn new(severity: Severity, text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            severity,
+            duration: None,
+        }
+    }
+
+    pub fn info(text: impl Into<String>) -> Self {
+        Self::new(Severity::Info, text)
+    }
+
+    pub fn warning(text: impl Into<String>) -> Self {
+        Self::new(Severity::Warning, text)
+    }
+
+    pub fn error(text: impl Into<String>) -> Self {
+        Self::new(Severity::Error, text)
+    }
+
+    /// Remove it after this long, unless dismissed before.
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+}
+
+struct ActiveNotification {
+    id: u64,
+    notification: Notification,
+    expires_at: Option<Instant>,
+}
+
+/// The notifications of one session.
+#[derive(Default)]
+pub(crate) struct Notifications {
+    next_id: u64,
+    active: Vec<ActiveNotification>,
+    /// Something was dismissed, so the frame we just painted is out of date.
+    needs_repaint: bool,
+    /// Where we painted the dismiss buttons, for the clients whose clicks don't reach the ui.
+    dismiss_buttons: Vec<(u64, egui::Rect)>,
+}
+
+impl Notifications {
+    pub(crate) fn push(&mut self, notification: Notification) {
+        let expires_at = notification
+            .duration
+            .map(|duration| Instant::now() + duration);
+        self.active.push(ActiveNotification {
+            id: self.next_id,
+            notification,
+            expires_at,
+        });
+        self.next_id += 1;
+    }
+
+    /// When we next need a frame to remove an expired notification.
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.active
+            .iter()
+            .filter_map(|active| active.expires_at)
+            .min()
+    }
+
+    /// Paint the notifications on top of everything else, and handle dismissals.
+    pub(crate) fn show(&mut self, egui_ctx: &egui::Context) {
+        let now = Instant::now();
+        self.active
+            .retain(|active| !matches!(active.expires_at, Some(expires_at) if expires_at <= now));
+        self.dismiss_buttons.clear();
+        if self.active.is_empty() {
+            return;
+        }
+
+        let mut dismissed = vec![];
+        let dismiss_buttons = &mut self.dismiss_buttons;
+        egui::Area::new("eterm_notifications")
+            .order(egui::Order::Foreground)
+            .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-8.0, 8.0))
+            .show(egui_ctx, |ui| {
+                ui.set_max_width(320.0);
+                for active in &self.active {
+                    let visuals = ui.visuals();
+                    let color = match active.notification.severity {
+                        Severity::Info => visuals.text_color(),
+                        Severity::Warning => visuals.warn_fg_color,
+                        Severity::Error => visuals.error_fg_color,
+                    };
+                    egui::Frame::popup(ui.style())
+                        .stroke(egui::Stroke::new(1.0, color))
+                        .show(ui, |ui| {
+                            ui.horizontal(|ui| {
+                                ui.colored_label(color, &active.notification.text);
+                                let response = ui.small_button("✖").on_hover_text("Dismiss");
+                                if response.clicked() {
+                                    dismissed.push(active.id);
+                                }
+                                dismiss_buttons.push((active.id, response.rect));
+                            });
+                        });
+                }
+            });
+
+        if !dismissed.is_empty() {
+            self.active.retain(|active| !dismissed.contains(&active.id));
+            self.needs_repaint = true;
+        }
+    }
+
+    /// Dismiss the notifications whose dismiss buttons were clicked,
+    /// for clients (e.g. viewers) whose clicks are filtered out before they reach the ui.
+    ///
+    /// Returns `true` if anything was dismissed.
+    pub(crate) fn dismiss_clicked(&mut self, events: &[egui::Event]) -> bool {
+        let dismissed: Vec<u64> = events
+            .iter()
+            .filter_map(|event| match event {
+                egui::Event::PointerButton {
+                    pos,
+                    button: egui::PointerButton::Primary,
+                    pressed: false,
+                    ..
+                } => self
+                    .dismiss_buttons
+                    .iter()
+                    .find(|(_, rect)| rect.contains(*pos))
+                    .map(|(id, _)| *id),
+                _ => None,
+            })
+            .collect();
+        if dismissed.is_empty() {
+            return false;
+        }
+        self.active.retain(|active| !dismissed.contains(&active.id));
+        self.dismiss_buttons
+            .retain(|(id, _)| !dismissed.contains(id));
+        true
+    }
+
+    pub(crate) fn take_needs_repaint(&mut self) -> bool {
+        std::mem::take(&mut self.needs_repaint)
+    }
+}
+
+#[test]
+fn test_notification_expiry() {
+    let egui_ctx = egui::Context::default();
+    let mut notifications = Notifications::default();
+    notifications.push(Notification::info("Stays"));
+    notifications.push(Notification::error("Goes").with_duration(Duration::ZERO));
+    assert!(notifications.next_expiry().is_some());
+
+    let _ = egui_ctx.run(Default::default(), |egui_ctx| notifications.show(egui_ctx));
+
+    let texts: Vec<&str> = notifications
+        .active
+        .iter()
+        .map(|active| active.notification.text.as_str())
+        .collect();
+    assert_eq!(texts, vec!["Stays"]);
+    assert_eq!(notifications.next_expiry(), None);
+}
+
+#[test]
+fn test_viewer_can_dismiss() {
+    use crate::{messages::InputDelta, ClientId, ClientToServerMessage, Role};
+
+    let mut session = crate::server::Session::new(ClientId(0));
+    session.role = Role::Viewer;
+    session
+        .notifications
+        .push(Notification::info("Deploy starting"));
+
+    let input = |events: Vec<egui::Event>| ClientToServerMessage::Input {
+        input: InputDelta {
+            events,
+            screen_rect: Some(egui::Rect::from_min_size(
+                egui::Pos2::ZERO,
+                egui::vec2(800.0, 600.0),
+            )),
+            ..Default::default()
+        },
+        client_time: 0.0,
+    };
+    session.on_message(input(vec![]));
+    session.create_frame(&mut |_egui_ctx, _client_id| {});
+    let pos = session.notifications.dismiss_buttons[0].1.center();
+
+    let click = |pressed| egui::Event::PointerButton {
+        pos,
+        button: egui::PointerButton::Primary,
+        pressed,
+        modifiers: Default::default(),
+    };
+    session.on_message(input(vec![
+        egui::Event::PointerMoved(pos),
+        click(true),
+        click(false),
+    ]));
+    session.create_frame(&mut |_egui_ctx, _client_id| {});
+    assert!(session.notifications.active.is_empty());
+}
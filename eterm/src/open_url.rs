@@ -0,0 +1,226 @@
+//! What a client does when the server ui asks to open a url
+//! ([`egui::PlatformOutput::open_url`]), e.g. because a [`egui::Hyperlink`] was clicked.
+//!
+//! A server could otherwise make the client open any url it likes.
+
+use egui::output::OpenUrl;
+
+/// Which urls from the server a client opens.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OpenUrlPolicy {
+    /// Open all urls, like a local egui app would.
+    Allow,
+
+    /// Never open urls from the server.
+    #[default]
+    Block,
+
+    /// Open the urls on the allowlist, block the rest.
+    Allowlist(UrlAllowlist),
+
+    /// Open the urls on the allowlist, and ask the user about the rest
+    /// (see [`crate::Client::take_url_requests`]).
+    Ask(UrlAllowlist),
+}
+
+/// What to do with one url.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Open,
+    Block,
+    Ask,
+}
+
+impl OpenUrlPolicy {
+    pub(crate) fn verdict(&self, url: &str) -> Verdict {
+        match self {
+            Self::Allow => Verdict::Open,
+            Self::Block => Verdict::Block,
+            Self::Allowlist(allowlist) => {
+                if allowlist.allows(url) {
+                    Verdict::Open
+                } else {
+                    Verdict::Block
+                }
+            }
+            Self::Ask(allowlist) => {
+                if allowlist.allows(url) {
+                    Verdict::Open
+                } else {
+                    Verdict::Ask
+                }
+            }
+        }
+    }
+
+    /// Remove the url from the output unless it may be opened right away.
+    ///
+    /// Returns the url if the user should be asked about it.
+    pub(crate) fn apply(&self, platform_output: &mut egui::PlatformOutput) -> Option<OpenUrl> {
+        let open_url = platform_output.open_url.take()?;
+        match self.verdict(&open_url.url) {
+            Verdict::Open => {
+                platform_output.open_url = Some(open_url);
+                None
+            }
+            Verdict::Block => {
+                tracing::info!("Blocked the server from opening {:?}", open_url.url);
+                None
+            }
+            Verdict::Ask => Some(open_url),
+        }
+    }
+}
+
+/// Urls that may be opened without asking.
+///
+/// A url is allowed if its scheme is one of [`Self::schemes`],
+/// and its host (if it has one) is one of [`Self::hosts`] or a subdomain of one.
+/// So `schemes: ["https"], hosts: ["docs.rs"]` allows `https://docs.rs/egui`
+/// but not `http://docs.rs` or `https://docs.rs.evil.com`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UrlAllowlist {
+    /// E.g. `"https"` or `"mailto"`.
+    pub schemes: Vec<String>,
+
+    /// E.g. `"github.com"`.
+    pub hosts: Vec<String>,
+}
+
+impl UrlAllowlist {
+    pub fn allows(&self, url: &str) -> bool {
+        let (scheme, host) = match scheme_and_host(url) {
+            Some(parts) => parts,
+            None => return false,
+        };
+
+        if !self
+            .schemes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&scheme))
+        {
+            return false;
+        }
+
+        match host {
+            None => true,
+            Some(host) => self.hosts.iter().any(|allowed| {
+                let allowed = allowed.to_ascii_lowercase();
+                host == allowed || host.ends_with(&format!(".{}", allowed))
+            }),
+        }
+    }
+}
+
+/// The lowercase scheme and host of a url, if it looks well-formed.
+fn scheme_and_host(url: &str) -> Option<(String, Option<String>)> {
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+
+    let (scheme, rest) = url.split_once(':')?;
+    let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    if !valid_scheme {
+        return None;
+    }
+
+    let scheme = scheme.to_ascii_lowercase();
+
+    // Browsers accept `https:example.com` and `https:\\example.com` too:
+    let special = ["http", "https", "ws", "wss", "ftp"].contains(&scheme.as_str());
+    let authority = if special {
+        Some(rest.trim_start_matches(['/', '\\']))
+    } else {
+        rest.strip_prefix("//")
+    };
+
+    let host = match authority {
+        Some(rest) => {
+            // Browsers also end the authority at a backslash:
+            let authority = rest.split(['/', '\\', '?', '#']).next().unwrap_or_default();
+            let host_and_port = authority.rsplit('@').next().unwrap_or_default();
+            let host = if host_and_port.starts_with('[') {
+                // IPv6
+                host_and_port
+                    .split_inclusive(']')
+                    .next()
+                    .unwrap_or_default()
+            } else {
+                host_and_port.split(':').next().unwrap_or_default()
+            };
+            if host.is_empty() {
+                return None;
+            }
+            Some(host.to_ascii_lowercase())
+        }
+        None => None,
+    };
+
+    Some((scheme, host))
+}
+
+#[test]
+fn test_url_allowlist() {
+    let allowlist = UrlAllowlist {
+        schemes: vec!["https".to_owned(), "mailto".to_owned()],
+        hosts: vec!["docs.rs".to_owned()],
+    };
+
+    assert!(allowlist.allows("https://docs.rs/egui"));
+    assert!(allowlist.allows("HTTPS://Docs.RS"));
+    assert!(allowlist.allows("https://www.docs.rs:443/?q=1"));
+    assert!(allowlist.allows("mailto:someone@example.com"));
+
+    assert!(!allowlist.allows("http://docs.rs"));
+    assert!(!allowlist.allows("file:///etc/passwd"));
+    assert!(!allowlist.allows("https://docs.rs.evil.com"));
+    assert!(!allowlist.allows("https://docs.rs@evil.com"));
+    assert!(!allowlist.allows("https://evil.com\\@docs.rs"));
+    assert!(!allowlist.allows("https://notdocs.rs"));
+    assert!(!allowlist.allows("https://docs.rs/\nfoo"));
+    assert!(!allowlist.allows("https:evil.com"));
+    assert!(!allowlist.allows("https:/\\evil.com"));
+    assert!(!allowlist.allows("docs.rs"));
+}
+
+#[test]
+fn test_open_url_policy() {
+    let output = |url: &str| egui::PlatformOutput {
+        open_url: Some(OpenUrl::new_tab(url)),
+        ..Default::default()
+    };
+    let allowlist = UrlAllowlist {
+        schemes: vec!["https".to_owned()],
+        hosts: vec!["github.com".to_owned()],
+    };
+
+    let mut allowed = output("https://github.com/emilk/eterm");
+    assert_eq!(
+        OpenUrlPolicy::Ask(allowlist.clone())
+            .apply(&mut allowed)
+            .map(|open_url| open_url.url),
+        None
+    );
+    assert!(allowed.open_url.is_some());
+
+    let mut asked = output("https://example.com");
+    assert_eq!(
+        OpenUrlPolicy::Ask(allowlist.clone())
+            .apply(&mut asked)
+            .map(|open_url| open_url.url),
+        Some("https://example.com".to_owned())
+    );
+    assert!(asked.open_url.is_none());
+
+    let mut blocked = output("https://example.com");
+    assert_eq!(
+        OpenUrlPolicy::Allowlist(allowlist)
+            .apply(&mut blocked)
+            .map(|open_url| open_url.url),
+        None
+    );
+    assert!(blocked.open_url.is_none());
+}
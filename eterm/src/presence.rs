@@ -0,0 +1,71 @@
+use crate::ClientId;
+
+/// Longest name we accept from a client, in characters.
+pub(crate) const MAX_NAME_LEN: usize = 32;
+
+/// Where another client is pointing.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RemotePointer {
+    /// The id of the session the pointer belongs to.
+    pub(crate) client_id: ClientId,
+    pub(crate) pos: egui::Pos2,
+    pub(crate) name: Option<String>,
+}
+
+/// Make an untrusted name safe to show to others.
+pub(crate) fn sanitize_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Paint labeled cursors for everyone except `own`, on top of everything else.
+pub(crate) fn paint_remote_pointers(
+    egui_ctx: &egui::Context,
+    pointers: &[RemotePointer],
+    own: Option<ClientId>,
+) {
+    let layer_id = egui::LayerId::new(egui::Order::Tooltip, egui::Id::new("eterm_remote_pointers"));
+    let painter = egui_ctx.layer_painter(layer_id);
+    let font_id = egui::TextStyle::Small.resolve(&egui_ctx.style());
+
+    for pointer in pointers {
+        if Some(pointer.client_id) == own {
+            continue;
+        }
+
+        let color = pointer_color(pointer.client_id);
+        painter.circle_stroke(pointer.pos, 6.0, egui::Stroke::new(2.0, color));
+
+        let label = match &pointer.name {
+            Some(name) => name.clone(),
+            None => format!("Client {}", pointer.client_id),
+        };
+        let galley = painter.layout_no_wrap(label, font_id.clone(), egui::Color32::WHITE);
+        let text_pos = pointer.pos + egui::vec2(8.0, 8.0);
+        let background = egui::Rect::from_min_size(text_pos, galley.size()).expand(2.0);
+        painter.rect_filled(background, 2.0, color);
+        painter.galley(text_pos, galley);
+    }
+}
+
+/// A distinct color for each client.
+fn pointer_color(client_id: ClientId) -> egui::Color32 {
+    // The golden ratio spreads consecutive ids around the color wheel:
+    let hue = (client_id.0 as f32 * 0.618_034).fract();
+    egui::color::Hsva::new(hue, 0.7, 0.7, 1.0).into()
+}
+
+#[test]
+fn test_sanitize_name() {
+    assert_eq!(sanitize_name("  Ada\n "), Some("Ada".to_owned()));
+    assert_eq!(sanitize_name("\u{7}\t "), None);
+    assert_eq!(
+        sanitize_name(&"x".repeat(100)).map(|name| name.len()),
+        Some(MAX_NAME_LEN)
+    );
+}
@@ -0,0 +1,369 @@
+//! Per-client limits on how much a client may send us.
+
+use std::time::{Duration, Instant};
+
+/// Limits how many messages and bytes per second each client may send to the server.
+///
+/// Each limit is a token bucket which can hold `burst` seconds worth of tokens.
+/// A client that runs out of tokens is first throttled (we stop reading from it
+/// until its buckets have refilled), and if it spends a total of
+/// [`Self::disconnect_after`] waiting before its buckets are half full again,
+/// it is disconnected.
+///
+/// File transfer messages have a budget of their own, so that they don't eat into that of the input.
+/// Once that budget is spent, we wait for it to refill after each transfer message,
+/// but other messages are only held up by their own budget.
+/// [`crate::Client`] paces its uploads to stay below the default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Sustained number of messages per second.
+    pub messages_per_second: f32,
+
+    /// Sustained number of bytes per second, not counting file transfer messages.
+    pub bytes_per_second: f32,
+
+    /// Sustained number of bytes per second of file transfer messages.
+    pub transfer_bytes_per_second: f32,
+
+    /// How many seconds worth of messages and bytes a client may send in a burst.
+    pub burst: f32,
+
+    /// Disconnect a client that has spent this long waiting for its buckets to refill.
+    pub disconnect_after: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            messages_per_second: 500.0,
+            bytes_per_second: 4_000_000.0,
+            transfer_bytes_per_second: 4_000_000.0,
+            burst: 2.0,
+            disconnect_after: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What to do with the next message from a client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RateLimitVerdict {
+    /// Go ahead and read it.
+    Allow,
+
+    /// Don't read anything from the client for this long.
+    Throttle(Duration),
+
+    /// The client has been throttled for too long.
+    Disconnect,
+}
+
+struct TokenBucket {
+    rate: f32,
+    capacity: f32,
+    tokens: f32,
+}
+
+impl TokenBucket {
+    fn new(rate: f32, burst: f32) -> Self {
+        let capacity = rate * burst;
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+        }
+    }
+
+    fn refill(&mut self, dt: f32) {
+        self.tokens = (self.tokens + self.rate * dt).min(self.capacity);
+    }
+
+    /// How long until we have tokens again.
+    fn time_until_tokens(&self) -> Duration {
+        if self.tokens > 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f32((-self.tokens / self.rate).max(1e-3))
+        }
+    }
+
+    /// Can go into debt, so that a single large message is allowed.
+    fn consume(&mut self, amount: f32) {
+        self.tokens -= amount;
+    }
+
+    fn is_at_least_half_full(&self) -> bool {
+        self.tokens >= 0.5 * self.capacity
+    }
+}
+
+/// Keeps track of how much a client has sent us.
+pub(crate) struct RateLimiter {
+    /// Used for reporting.
+    client: String,
+    limit: RateLimit,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    transfer_bytes: TokenBucket,
+    /// Was the last message a file transfer message?
+    last_was_transfer: bool,
+    last_refill: Instant,
+    /// How long the client has waited since it was first throttled,
+    /// or `None` if it isn't throttled.
+    throttled_for: Option<Duration>,
+    /// The wait we asked for in the last check.
+    last_wait: Duration,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit, client: String) -> Self {
+        Self {
+            client,
+            messages: TokenBucket::new(limit.messages_per_second, limit.burst),
+            bytes: TokenBucket::new(limit.bytes_per_second, limit.burst),
+            transfer_bytes: TokenBucket::new(limit.transfer_bytes_per_second, limit.burst),
+            last_was_transfer: false,
+            limit,
+            last_refill: Instant::now(),
+            throttled_for: None,
+            last_wait: Duration::ZERO,
+        }
+    }
+
+    /// Call before reading each message.
+    pub(crate) fn check(&mut self, now: Instant) -> RateLimitVerdict {
+        let client = &self.client;
+        let dt = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.messages.refill(dt.as_secs_f32());
+        self.bytes.refill(dt.as_secs_f32());
+        self.transfer_bytes.refill(dt.as_secs_f32());
+
+        let mut wait = self
+            .messages
+            .time_until_tokens()
+            .max(self.bytes.time_until_tokens());
+        if self.last_was_transfer {
+            // Make the client pay for the transfer message we just read:
+            wait = wait.max(self.transfer_bytes.time_until_tokens());
+        }
+
+        // Only the time spent waiting counts, so that a client that stays
+        // under the limit after a burst is not disconnected:
+        let waited = dt.min(std::mem::replace(&mut self.last_wait, wait));
+
+        if let Some(throttled_for) = &mut self.throttled_for {
+            *throttled_for += waited;
+            if *throttled_for >= self.limit.disconnect_after {
+                tracing::warn!(
+                    "{} has exceeded its rate limit for {:.1} s. Disconnecting.",
+                    client,
+                    self.limit.disconnect_after.as_secs_f32()
+                );
+                return RateLimitVerdict::Disconnect;
+            }
+
+            // A client that keeps sending at the limit would drain its buckets
+            // as soon as they get any tokens, so we only forgive it once they have
+            // refilled properly:
+            if self.messages.is_at_least_half_full()
+                && self.bytes.is_at_least_half_full()
+                && (!self.last_was_transfer || self.transfer_bytes.is_at_least_half_full())
+            {
+                tracing::info!("{} is no longer throttled", client);
+                self.throttled_for = None;
+            }
+        } else if wait > Duration::ZERO {
+            tracing::warn!(
+                "{} exceeded its rate limit ({} messages/s, {:.1} MB/s). Throttling.",
+                client,
+                self.limit.messages_per_second,
+                self.limit.bytes_per_second * 1e-6
+            );
+            self.throttled_for = Some(Duration::ZERO);
+        }
+
+        if wait > Duration::ZERO {
+            RateLimitVerdict::Throttle(wait)
+        } else {
+            RateLimitVerdict::Allow
+        }
+    }
+
+    /// Call after reading a message of this many bytes.
+    pub(crate) fn consume_message(
+        &mut self,
+        num_bytes: usize,
+        message: &crate::ClientToServerMessage,
+    ) {
+        if let crate::ClientToServerMessage::Transfer(_) = message {
+            self.consume_transfer(num_bytes);
+        } else {
+            self.consume(num_bytes);
+        }
+    }
+
+    fn consume(&mut self, num_bytes: usize) {
+        self.last_was_transfer = false;
+        self.messages.consume(1.0);
+        self.bytes.consume(num_bytes as f32);
+    }
+
+    fn consume_transfer(&mut self, num_bytes: usize) {
+        self.last_was_transfer = true;
+        self.transfer_bytes.consume(num_bytes as f32);
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let mut limiter = RateLimiter::new(
+        RateLimit {
+            messages_per_second: 10.0,
+            bytes_per_second: 1000.0,
+            transfer_bytes_per_second: 1000.0,
+            burst: 1.0,
+            disconnect_after: Duration::from_secs(2),
+        },
+        "test".to_owned(),
+    );
+    let start = limiter.last_refill;
+    let at = |seconds: f32| start + Duration::from_secs_f32(seconds);
+
+    // A burst is fine:
+    for _ in 0..10 {
+        assert_eq!(limiter.check(at(0.0)), RateLimitVerdict::Allow);
+        limiter.consume(10);
+    }
+
+    // …but then we are out of messages:
+    assert!(matches!(
+        limiter.check(at(0.0)),
+        RateLimitVerdict::Throttle(_)
+    ));
+
+    // Keep flooding above the refill rate:
+    let mut t = 0.0;
+    let verdict = loop {
+        t += 0.1;
+        let verdict = limiter.check(at(t));
+        if verdict == RateLimitVerdict::Allow {
+            for _ in 0..10 {
+                limiter.consume(10);
+            }
+        } else if verdict == RateLimitVerdict::Disconnect {
+            break verdict;
+        }
+        assert!(t < 5.0, "Should have been disconnected by now");
+    };
+    assert_eq!(verdict, RateLimitVerdict::Disconnect);
+
+    // A single large message is allowed, but then throttles:
+    let mut limiter = RateLimiter::new(RateLimit::default(), "test".to_owned());
+    let now = Instant::now();
+    assert_eq!(limiter.check(now), RateLimitVerdict::Allow);
+    limiter.consume(100_000_000);
+    assert!(matches!(limiter.check(now), RateLimitVerdict::Throttle(_)));
+}
+
+#[test]
+fn test_rate_limiter_forgives_steady_clients() {
+    let mut limiter = RateLimiter::new(
+        RateLimit {
+            messages_per_second: 10.0,
+            bytes_per_second: 1000.0,
+            transfer_bytes_per_second: 1000.0,
+            burst: 1.0,
+            disconnect_after: Duration::from_secs(2),
+        },
+        "test".to_owned(),
+    );
+    let start = limiter.last_refill;
+    let at = |seconds: f32| start + Duration::from_secs_f32(seconds);
+
+    // Empty both buckets in one burst…
+    for _ in 0..10 {
+        assert_eq!(limiter.check(at(0.0)), RateLimitVerdict::Allow);
+        limiter.consume(100);
+    }
+    assert!(matches!(
+        limiter.check(at(0.0)),
+        RateLimitVerdict::Throttle(_)
+    ));
+
+    // …then send at about 90% of the limit for much longer than `disconnect_after`:
+    let mut t = 0.0;
+    while t < 30.0 {
+        t += 0.11;
+        match limiter.check(at(t)) {
+            RateLimitVerdict::Allow => limiter.consume(95),
+            RateLimitVerdict::Throttle(_) => {}
+            RateLimitVerdict::Disconnect => panic!("Disconnected at {} s", t),
+        }
+    }
+    assert!(limiter.throttled_for.is_none());
+}
+
+#[test]
+fn test_paced_upload_is_not_throttled() {
+    use crate::transfer::{Pacer, CHUNK_SIZE, UPLOAD_BYTES_PER_SECOND};
+
+    let limit = RateLimit::default();
+    let mut limiter = RateLimiter::new(limit.clone(), "test".to_owned());
+    let start = limiter.last_refill;
+    let mut pacer = Pacer::new(UPLOAD_BYTES_PER_SECOND, start);
+
+    // Larger than the burst, with input on the side, checked every 5 ms like the client sends:
+    let file_size = 3.0 * limit.transfer_bytes_per_second * limit.burst;
+    let mut uploaded = 0.0;
+    let mut t = Duration::ZERO;
+    while uploaded < file_size {
+        t += Duration::from_millis(5);
+        let now = start + t;
+        assert_eq!(limiter.check(now), RateLimitVerdict::Allow, "at {:?}", t);
+        limiter.consume(200); // some mouse movement
+
+        while pacer.ready(now) {
+            assert_eq!(limiter.check(now), RateLimitVerdict::Allow, "at {:?}", t);
+            pacer.consume(CHUNK_SIZE);
+            limiter.consume_transfer(CHUNK_SIZE + 100); // with some overhead
+            uploaded += CHUNK_SIZE as f32;
+        }
+    }
+}
+
+#[test]
+fn test_transfer_debt_does_not_hold_up_input() {
+    let mut limiter = RateLimiter::new(
+        RateLimit {
+            messages_per_second: 10.0,
+            bytes_per_second: 1000.0,
+            transfer_bytes_per_second: 1000.0,
+            burst: 1.0,
+            disconnect_after: Duration::from_secs(2),
+        },
+        "test".to_owned(),
+    );
+    let start = limiter.last_refill;
+    let at = |seconds: f32| start + Duration::from_secs_f32(seconds);
+
+    // A large transfer message makes us wait before reading the next message…
+    assert_eq!(limiter.check(at(0.0)), RateLimitVerdict::Allow);
+    limiter.consume_transfer(3000);
+    assert!(matches!(
+        limiter.check(at(0.0)),
+        RateLimitVerdict::Throttle(_)
+    ));
+
+    // …but the input after it is only limited by its own budget:
+    limiter.consume(10);
+    assert_eq!(limiter.check(at(0.1)), RateLimitVerdict::Allow);
+    assert!(limiter.transfer_bytes.time_until_tokens() > Duration::ZERO);
+
+    // The next transfer message waits for the budget again:
+    limiter.consume_transfer(100);
+    assert!(matches!(
+        limiter.check(at(0.2)),
+        RateLimitVerdict::Throttle(_)
+    ));
+    assert_eq!(limiter.check(at(2.5)), RateLimitVerdict::Allow);
+}
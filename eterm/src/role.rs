@@ -0,0 +1,95 @@
+//! What each client is allowed to do.
+
+/// What a client is allowed to do.
+///
+/// Assigned per connection with `Server::set_role_fn`, and changeable with
+/// `Server::set_client_role` (e.g. after the client has logged in through your ui).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, serde::Serialize)]
+pub enum Role {
+    /// May do anything the ui allows.
+    #[default]
+    Operator,
+
+    /// Read-only: may hover and scroll, but clicks, keys and text are discarded by the server,
+    /// and so are uploads and custom messages.
+    Viewer,
+}
+
+impl Role {
+    /// The role of the client whose ui is currently being shown.
+    ///
+    /// Call this from within the `do_ui` closure to hide or disable things for viewers:
+    ///
+    /// ```
+    /// # let egui_ctx = egui::Context::default();
+    /// # let _ = egui_ctx.run(Default::default(), |egui_ctx| {
+    /// egui::CentralPanel::default().show(egui_ctx, |ui| {
+    ///     let is_operator = eterm::Role::of(egui_ctx) == eterm::Role::Operator;
+    ///     ui.add_enabled(is_operator, egui::Button::new("Restart service"));
+    /// });
+    /// # });
+    /// ```
+    pub fn of(egui_ctx: &egui::Context) -> Self {
+        egui_ctx.data().get_temp(Self::id()).unwrap_or_default()
+    }
+
+    pub(crate) fn set(self, egui_ctx: &egui::Context) {
+        egui_ctx.data().insert_temp(Self::id(), self);
+    }
+
+    fn id() -> egui::Id {
+        egui::Id::new("eterm::Role")
+    }
+
+    /// Remove all input a client with this role may not send.
+    pub(crate) fn filter_input(self, input: &mut crate::messages::InputDelta) {
+        match self {
+            Self::Operator => {}
+            Self::Viewer => {
+                input.events.retain(is_read_only_event);
+            }
+        }
+    }
+}
+
+/// Events that can't change anything.
+fn is_read_only_event(event: &egui::Event) -> bool {
+    matches!(
+        event,
+        egui::Event::PointerMoved(_)
+            | egui::Event::PointerGone
+            | egui::Event::Scroll(_)
+            | egui::Event::Zoom(_)
+    )
+}
+
+#[test]
+fn test_viewer_input_filter() {
+    let mut input = crate::messages::InputDelta {
+        events: vec![
+            egui::Event::PointerMoved(egui::pos2(1.0, 2.0)),
+            egui::Event::PointerButton {
+                pos: egui::pos2(1.0, 2.0),
+                button: egui::PointerButton::Primary,
+                pressed: true,
+                modifiers: Default::default(),
+            },
+            egui::Event::Text("rm -rf".to_owned()),
+            egui::Event::Scroll(egui::vec2(0.0, 10.0)),
+        ],
+        ..Default::default()
+    };
+
+    let mut operator_input = input.clone();
+    Role::Operator.filter_input(&mut operator_input);
+    assert_eq!(operator_input, input);
+
+    Role::Viewer.filter_input(&mut input);
+    assert_eq!(
+        input.events,
+        vec![
+            egui::Event::PointerMoved(egui::pos2(1.0, 2.0)),
+            egui::Event::Scroll(egui::vec2(0.0, 10.0)),
+        ]
+    );
+}
@@ -1,12 +1,24 @@
 use crate::{
+    audit::SessionAudit,
+    custom::CustomHandlers,
     messages::{into_clipped_net_meshes, ClippedNetMesh},
-    ClientToServerMessage, ServerToClientMessage,
+    notifications::{Notification, Notifications, Target},
+    presence::{paint_remote_pointers, RemotePointer},
+    rate_limit::{RateLimit, RateLimitVerdict, RateLimiter},
+    textures::TextureMirror,
+    transfer::{
+        Direction, TransferId, TransferMessage, TransferProgress, Transfers, CHUNKS_PER_TICK,
+    },
+    AuditLog, Capabilities, ClientToServerMessage, CustomMessage, EtermFrame, InputLimits, Role,
+    ServerToClientMessage, SessionInfo, SharedAccessList, ViolationPolicy,
 };
 use anyhow::Context as _;
 use egui::RawInput;
+use parking_lot::Mutex;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     net::{SocketAddr, TcpListener},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
@@ -14,15 +26,73 @@ use std::{
 pub const DEFAULT_MAX_UPDATE_INTERVAL: Duration = Duration::from_millis(1000 / 60);
 // Send at least 1 frame per second
 pub const DEFAULT_MIN_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+// In a shared session, someone else can take the pointer after this long without input
+const SHARED_POINTER_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct ClientId(u64);
+/// Identifies the session of a client, and is passed to your ui code.
+///
+/// Each connection gets a new id. The id belongs to the session though,
+/// so when a driver hands their session off to a spectator (see [`crate::Client::hand_off`])
+/// the two connections swap ids.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ClientId(pub(crate) u64);
+
+impl std::fmt::Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::str::FromStr for ClientId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+/// Decides the [`Role`] of a new connection.
+pub(crate) type RoleFn = dyn Fn(SocketAddr) -> Role + Send + Sync;
+
+/// How the input received from a client is fed to egui.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum InputMode {
+    /// All input received since the last frame is merged into as few egui passes as possible.
+    ///
+    /// Input batches with pointer button or scroll events end a pass,
+    /// so that egui sees when each click and scroll happened.
+    /// Other input is merged, so a fast drag collapses into a single pointer position per frame.
+    #[default]
+    Merged,
+
+    /// Run one egui pass for each input batch received from the client,
+    /// so that e.g. drawing tools and sliders see every pointer sample.
+    ///
+    /// Only the last pass is painted and sent to the client.
+    Lossless {
+        /// Keep the platform output (copied text, opened urls, …) of the intermediate passes.
+        ///
+        /// If `false`, only the platform output of the last pass is sent to the client.
+        /// Texture updates are always kept.
+        keep_intermediate_output: bool,
+    },
+}
 
 pub struct Server {
     next_client_id: u64,
     tcp_listener: TcpListener,
     clients: HashMap<SocketAddr, Client>,
     minimum_update_interval: Duration,
+    input_mode: InputMode,
+    input_limits: InputLimits,
+    rate_limit: Option<RateLimit>,
+    access_list: SharedAccessList,
+    audit_log: Option<Arc<Mutex<AuditLog>>>,
+    role_fn: Arc<RoleFn>,
+    shared: Option<SharedSession>,
+    presence: bool,
+    allow_spectators: bool,
+    custom_handlers: CustomHandlers<ClientId>,
 }
 
 impl Server {
@@ -41,15 +111,332 @@ impl Server {
             tcp_listener,
             clients: Default::default(),
             minimum_update_interval: DEFAULT_MIN_UPDATE_INTERVAL,
+            input_mode: Default::default(),
+            input_limits: Default::default(),
+            rate_limit: None,
+            access_list: Default::default(),
+            audit_log: None,
+            role_fn: Arc::new(|_| Role::Operator),
+            shared: None,
+            presence: false,
+            allow_spectators: false,
+            custom_handlers: Default::default(),
         })
     }
 
+    /// The address we are listening on.
+    ///
+    /// # Errors
+    /// Underlying TCP errors.
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.tcp_listener.local_addr()?)
+    }
+
     /// Send a new frame to each client at least this often.
     /// Default: one second.
     pub fn set_minimum_update_interval(&mut self, minimum_update_interval: Duration) {
         self.minimum_update_interval = minimum_update_interval;
     }
 
+    /// How to feed client input to egui, for all current and future clients.
+    /// Default: [`InputMode::Merged`].
+    pub fn set_input_mode(&mut self, input_mode: InputMode) {
+        self.input_mode = input_mode;
+        for client in self.clients.values_mut() {
+            client.session.input_mode = input_mode;
+        }
+        if let Some(shared) = &mut self.shared {
+            shared.session.input_mode = input_mode;
+        }
+    }
+
+    /// Show a notification on top of the ui of one or all clients.
+    ///
+    /// In a shared session, [`Target::All`] means the shared context.
+    pub fn notify(&mut self, target: Target, notification: Notification) {
+        if let Some(shared) = &mut self.shared {
+            if target.includes(shared.session.client_id) {
+                shared.session.notifications.push(notification);
+                shared.session.request_repaint();
+                return;
+            }
+        }
+
+        for client in self.clients.values_mut() {
+            if client.tcp_endpoint.is_some() && target.includes(client.session.client_id) {
+                client.session.notifications.push(notification.clone());
+                client.session.request_repaint();
+            }
+        }
+    }
+
+    /// Handle the [`CustomMessage`]s clients send on this channel
+    /// (see [`crate::Client::send_custom`]).
+    ///
+    /// The handler is called from [`Self::show`] with the sender and the decoded value.
+    /// Messages that can't be decoded as a `T` are logged and dropped,
+    /// as are all messages from [`Role::Viewer`]s.
+    pub fn on_custom<T: serde::de::DeserializeOwned>(
+        &mut self,
+        channel: impl Into<String>,
+        handler: impl FnMut(ClientId, T) + Send + 'static,
+    ) {
+        self.custom_handlers.insert(channel.into(), handler);
+    }
+
+    /// Send a value on an application-defined channel to one client,
+    /// which receives it with [`crate::Client::on_custom`].
+    ///
+    /// # Errors
+    /// If the value can't be encoded, the client is not connected,
+    /// or it doesn't support the `"custom"` [feature](crate::capabilities::FEATURES).
+    pub fn send_custom<T: serde::Serialize + ?Sized>(
+        &mut self,
+        client_id: ClientId,
+        channel: impl Into<String>,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let message = ServerToClientMessage::Custom(CustomMessage::new(channel, value)?);
+        let client = self
+            .clients
+            .values_mut()
+            .find(|client| client.session.client_id == client_id && client.tcp_endpoint.is_some())
+            .with_context(|| format!("Client {} is not connected", client_id))?;
+        anyhow::ensure!(
+            client.session.capabilities.has_feature("custom"),
+            "Client {} doesn't support custom messages",
+            client_id
+        );
+        client.send_message(&message);
+        Ok(())
+    }
+
+    /// Send a file to a client, e.g. a report or profile the service generated.
+    ///
+    /// It is sent in the background, a few chunks at a time, as you keep calling [`Self::show`].
+    /// The client receives it with [`crate::Client::take_downloads`].
+    ///
+    /// # Errors
+    /// If the client is not connected,
+    /// or doesn't support the `"transfer"` [feature](crate::capabilities::FEATURES).
+    pub fn send_file(
+        &mut self,
+        client_id: ClientId,
+        name: impl Into<String>,
+        bytes: Vec<u8>,
+    ) -> anyhow::Result<TransferId> {
+        let client = self
+            .clients
+            .values_mut()
+            .find(|client| client.session.client_id == client_id && client.tcp_endpoint.is_some())
+            .with_context(|| format!("Client {} is not connected", client_id))?;
+        anyhow::ensure!(
+            client.session.capabilities.has_feature("transfer"),
+            "Client {} doesn't support file transfers",
+            client_id
+        );
+        Ok(client.session.transfers.send(name.into(), bytes))
+    }
+
+    /// Stop an upload from, or a download to, this client.
+    ///
+    /// Returns `false` if there is no such transfer in progress.
+    pub fn cancel_transfer(&mut self, client_id: ClientId, id: TransferId) -> bool {
+        self.clients
+            .values_mut()
+            .filter(|client| client.session.client_id == client_id)
+            .any(|client| {
+                client
+                    .session
+                    .transfers
+                    .cancel(id, "Cancelled by the server")
+            })
+    }
+
+    /// The uploads and downloads in progress of this client.
+    ///
+    /// From within `do_ui`, use [`crate::transfer::progress`] instead.
+    pub fn transfers(&self, client_id: ClientId) -> Vec<TransferProgress> {
+        self.clients
+            .values()
+            .filter(|client| client.session.client_id == client_id)
+            .flat_map(|client| client.session.transfers.progress())
+            .collect()
+    }
+
+    /// What a client told us about itself when it connected.
+    ///
+    /// From within `do_ui`, use [`Capabilities::of`] instead.
+    pub fn capabilities(&self, client_id: ClientId) -> Option<Capabilities> {
+        self.clients
+            .values()
+            .find(|client| client.session.client_id == client_id)
+            .map(|client| client.session.capabilities.clone())
+    }
+
+    /// Send a value on an application-defined channel to all connected clients
+    /// that support the `"custom"` [feature](crate::capabilities::FEATURES).
+    ///
+    /// # Errors
+    /// If the value can't be encoded.
+    pub fn broadcast_custom<T: serde::Serialize + ?Sized>(
+        &mut self,
+        channel: impl Into<String>,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let message = ServerToClientMessage::Custom(CustomMessage::new(channel, value)?);
+        let packet = crate::encode_message(&message)?;
+        for client in self.clients.values_mut() {
+            if client.session.capabilities.has_feature("custom") {
+                client.send_packet(&packet);
+            }
+        }
+        Ok(())
+    }
+
+    /// Show everyone where the other clients are pointing, labeled with their names
+    /// (see [`crate::Client::set_name`]).
+    ///
+    /// Off by default. In a shared session the other pointers are always shown.
+    pub fn set_presence(&mut self, presence: bool) {
+        self.presence = presence;
+    }
+
+    /// Let clients watch the sessions of others (see [`crate::Client::spectate`]).
+    ///
+    /// Off by default, in which case spectate requests are refused with a notification.
+    ///
+    /// To bring new spectators up to date, each session then keeps a copy of all its textures,
+    /// so call this before clients connect: sessions created before can't be spectated.
+    pub fn set_allow_spectators(&mut self, allow_spectators: bool) {
+        self.allow_spectators = allow_spectators;
+    }
+
+    /// Let all clients use one and the same [`egui::Context`], so they can work together.
+    ///
+    /// Everyone gets the same frame, fitted to the smallest screen.
+    /// One client at a time drives the pointer and keyboard;
+    /// another can take over once they have been idle for a second.
+    /// The pointers of the others are shown with their names.
+    ///
+    /// `do_ui` is called with the [`ClientId`] of the client that drives,
+    /// or with an id of its own for the shared context while nobody does.
+    /// Spectating is not possible in this mode, but [`crate::Client::hand_off`]
+    /// passes the pointer to another client.
+    pub fn set_shared_session(&mut self, shared: bool) {
+        if shared == self.shared.is_some() {
+            return;
+        }
+
+        self.shared = if shared {
+            let mut session = Session::new(ClientId(self.next_client_id));
+            self.next_client_id += 1;
+            session.input_mode = self.input_mode;
+            session.mirror_textures(); // for clients joining later
+            Some(SharedSession::new(session))
+        } else {
+            None
+        };
+
+        // Everyone is switching context:
+        for client in self.clients.values_mut() {
+            client.needs_full_textures = true;
+            if !shared && !client.session.has_texture_mirror() {
+                // Its textures were overwritten by those of the shared session:
+                client.session.reset_context();
+            }
+            client.session.request_frame();
+        }
+    }
+
+    /// Limits on the input we accept from clients, for all current and future clients.
+    ///
+    /// The input clients send is untrusted, and is checked against these limits
+    /// before it reaches egui.
+    pub fn set_input_limits(&mut self, input_limits: InputLimits) {
+        for client in self.clients.values_mut() {
+            client.session.input_limits = input_limits.clone();
+        }
+        self.input_limits = input_limits;
+    }
+
+    /// Limit how much each client may send us, or `None` for no limit.
+    /// Applies to new connections.
+    ///
+    /// Default: no limit. [`RateLimit::default`] is a good start.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limit = rate_limit;
+    }
+
+    /// Which addresses may connect. Everyone by default.
+    ///
+    /// Use the returned handle to update the lists at runtime (also from other threads).
+    /// Rejected connections are closed before any client state is created,
+    /// and connected clients that the new lists deny are disconnected in the next [`Self::show`].
+    ///
+    /// ``` no_run
+    /// let server = eterm::Server::new("0.0.0.0:8505")?;
+    /// server
+    ///     .access_list()
+    ///     .set(eterm::AccessList::parse(&["10.0.0.0/8"], &[])?);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn access_list(&self) -> SharedAccessList {
+        self.access_list.clone()
+    }
+
+    /// Record connections and what each client clicks and types.
+    /// Applies to new connections.
+    ///
+    /// ``` no_run
+    /// let mut server = eterm::Server::new("0.0.0.0:8505")?;
+    /// server.set_audit_log(Some(eterm::AuditLog::json_lines("eterm_audit.jsonl")?));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn set_audit_log(&mut self, audit_log: Option<AuditLog>) {
+        self.audit_log = audit_log.map(|audit_log| Arc::new(Mutex::new(audit_log)));
+    }
+
+    /// Decide the [`Role`] of each new connection based on its address.
+    ///
+    /// By default everyone is a [`Role::Operator`].
+    ///
+    /// ``` no_run
+    /// let mut server = eterm::Server::new("0.0.0.0:8505")?;
+    /// server.set_role_fn(|addr| {
+    ///     if addr.ip().is_loopback() {
+    ///         eterm::Role::Operator
+    ///     } else {
+    ///         eterm::Role::Viewer
+    ///     }
+    /// });
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn set_role_fn(&mut self, role_fn: impl Fn(SocketAddr) -> Role + Send + Sync + 'static) {
+        self.role_fn = Arc::new(role_fn);
+    }
+
+    /// Change the [`Role`] of a connected client,
+    /// e.g. after they have authenticated through your ui.
+    pub fn set_client_role(&mut self, client_id: ClientId, role: Role) {
+        for client in self.clients.values_mut() {
+            if client.session.client_id == client_id {
+                tracing::info!("{} is now {:?}", client.info(), role);
+                client.session.role = role;
+            }
+        }
+    }
+
+    /// How to feed input from one specific client to egui,
+    /// overriding [`Self::set_input_mode`].
+    pub fn set_client_input_mode(&mut self, client_id: ClientId, input_mode: InputMode) {
+        for client in self.clients.values_mut() {
+            if client.session.client_id == client_id {
+                client.session.input_mode = input_mode;
+            }
+        }
+    }
+
     /// Call frequently (e.g. 60 times per second) with the ui you'd like to show to clients.
     ///
     /// # Errors
@@ -60,51 +447,533 @@ impl Server {
 
     fn show_dyn(&mut self, do_ui: &mut dyn FnMut(&egui::Context, ClientId)) -> anyhow::Result<()> {
         self.accept_new_clients()?;
+        self.disconnect_denied_clients();
         self.try_receive();
+        self.remove_disconnected_clients();
+        for client in self.clients.values_mut() {
+            client.send_transfer_messages();
+        }
+
+        if self.shared.is_some() {
+            self.show_shared(do_ui);
+            self.send_session_infos();
+            return Ok(());
+        }
+
+        self.detach_orphaned_spectators();
+
+        // Which sessions are watched, and whether a new spectator needs all the textures:
+        let mut spectated: HashMap<ClientId, bool> = HashMap::new();
+        for client in self.clients.values() {
+            if let (Some(target), Some(_)) = (client.spectating, &client.tcp_endpoint) {
+                *spectated.entry(target).or_default() |= client.needs_full_textures;
+            }
+        }
+
+        let pointers = if self.presence {
+            let moved: HashSet<ClientId> = self
+                .clients
+                .values_mut()
+                .filter_map(|client| {
+                    let moved = client.session.take_pointer_moved();
+                    moved.then_some(client.session.client_id)
+                })
+                .collect();
+            for client in self.clients.values_mut() {
+                if moved.iter().any(|id| *id != client.session.client_id) {
+                    client.session.request_repaint(); // so they see the others move
+                }
+            }
+            remote_pointers(&self.clients)
+        } else {
+            vec![]
+        };
 
+        let mut frames = HashMap::new();
+        for client in self.clients.values_mut() {
+            if client.spectating.is_none() {
+                let spectated = spectated.get(&client.session.client_id).copied();
+                if let Some(frame) =
+                    client.show(do_ui, self.minimum_update_interval, spectated, &pointers)
+                {
+                    frames.insert(client.session.client_id, frame);
+                }
+            }
+        }
         for client in self.clients.values_mut() {
-            client.show(do_ui, self.minimum_update_interval);
+            if let Some(target) = client.spectating {
+                client.session.discard_input();
+                if let Some(frame) = frames.get(&target) {
+                    client.show_spectated(frame);
+                }
+            }
         }
+
+        self.send_session_infos();
         Ok(())
     }
 
+    /// Feed the input of all clients to the shared context, and send everyone the result.
+    fn show_shared(&mut self, do_ui: &mut dyn FnMut(&egui::Context, ClientId)) {
+        let Self {
+            clients,
+            shared,
+            minimum_update_interval,
+            ..
+        } = self;
+        let shared = match shared {
+            Some(shared) => shared,
+            None => return,
+        };
+        let now = Instant::now();
+
+        // Everyone gets the same frame, so it must fit all screens:
+        let screen_rect = clients
+            .values()
+            .filter(|client| client.tcp_endpoint.is_some())
+            .filter_map(|client| client.session.screen_rect())
+            .reduce(|a, b| egui::Rect::from_min_size(a.min, a.size().min(b.size())));
+        if screen_rect.is_some() && screen_rect != shared.screen_rect {
+            shared.screen_rect = screen_rect;
+            shared.session.append_input(RawInput {
+                screen_rect,
+                ..Default::default()
+            });
+        }
+
+        if let Some(active) = shared.active {
+            let still_here = clients
+                .values()
+                .any(|client| client.session.client_id == active && client.tcp_endpoint.is_some());
+            if !still_here {
+                shared.active = None;
+                shared.buttons_down.remove(&active);
+            }
+        }
+
+        // The ui is laid out for whoever is driving:
+        shared.session.capabilities = clients
+            .values()
+            .find(|client| Some(client.session.client_id) == shared.active)
+            .map(|client| client.session.capabilities.clone())
+            .unwrap_or_default();
+        for client in clients.values_mut() {
+            if client.tcp_endpoint.is_none() {
+                continue;
+            }
+            if client.needs_full_textures {
+                shared.session.request_frame();
+            }
+
+            let id = client.session.client_id;
+            let inputs = std::mem::take(&mut client.session.new_input);
+            let client_time = client.session.last_client_time.take();
+            let has_events = shared.track_buttons(id, &inputs);
+            if client.session.take_pointer_moved() && shared.active != Some(id) {
+                shared.session.request_repaint(); // so everyone sees them move
+            }
+
+            if has_events && client.session.role == Role::Operator && shared.can_take_over(id, now)
+            {
+                tracing::info!("{} now has the pointer", client.info());
+                shared.active = Some(id);
+            }
+
+            if shared.active == Some(id) {
+                if has_events {
+                    shared.last_active_event = now;
+                }
+                for mut input in inputs {
+                    input.screen_rect = shared.screen_rect;
+                    shared.session.append_input(input);
+                }
+                if client_time.is_some() {
+                    shared.session.last_client_time = client_time;
+                }
+            }
+        }
+
+        if !shared.session.wants_frame(*minimum_update_interval) {
+            return;
+        }
+
+        // The ui runs as whoever is driving, and what they do goes into their audit trail.
+        // Without a driver, any operator may take the pointer.
+        let active_addr = clients
+            .iter()
+            .find(|(_, client)| Some(client.session.client_id) == shared.active)
+            .map(|(addr, _)| *addr);
+        let any_operator = clients
+            .values()
+            .any(|client| client.tcp_endpoint.is_some() && client.session.role == Role::Operator);
+        shared.session.role = match active_addr.and_then(|addr| clients.get_mut(&addr)) {
+            Some(active) => {
+                shared.session.audit = active.session.audit.take();
+                active.session.role
+            }
+            None if any_operator => Role::Operator,
+            None => Role::Viewer,
+        };
+
+        // The active pointer is the real one, the others we draw:
+        let pointers = remote_pointers(clients);
+        let active = shared.active;
+        let frame = shared.session.create_frame(&mut |egui_ctx, client_id| {
+            do_ui(egui_ctx, active.unwrap_or(client_id));
+            paint_remote_pointers(egui_ctx, &pointers, active);
+        });
+
+        if let Some(active) = active_addr.and_then(|addr| clients.get_mut(&addr)) {
+            active.session.audit = shared.session.audit.take();
+        }
+
+        // Encoded once for all passive clients.
+        // The platform output (copied text, opened urls, …) is only for the active one.
+        let encode = |message: &ServerToClientMessage| match crate::encode_message(message) {
+            Ok(packet) => Some(packet),
+            Err(err) => {
+                tracing::error!(
+                    "Failed to encode frame: {}",
+                    crate::error_display_chain(err.as_ref())
+                );
+                None
+            }
+        };
+        let broadcast = |textures_delta: &egui::TexturesDelta| {
+            encode(&ServerToClientMessage::Frame {
+                frame_index: frame.frame_index,
+                platform_output: Default::default(),
+                clipped_net_mesh: frame.clipped_net_mesh.clone(),
+                client_time: None,
+                textures_delta: textures_delta.clone(),
+            })
+        };
+        let mut packet = None;
+        let mut full_textures_packet = None;
+
+        for client in clients.values_mut() {
+            if client.tcp_endpoint.is_none() {
+                continue;
+            }
+            let needs_full_textures = std::mem::take(&mut client.needs_full_textures);
+
+            let packet = if shared.active == Some(client.session.client_id) {
+                let mut frame = EtermFrame {
+                    frame_index: frame.frame_index,
+                    platform_output: frame.platform_output.clone(),
+                    clipped_net_mesh: frame.clipped_net_mesh.clone(),
+                    textures_delta: frame.textures_delta.clone(),
+                };
+                if needs_full_textures {
+                    if let Some(full_textures_delta) = shared.session.full_textures_delta() {
+                        frame.textures_delta = full_textures_delta;
+                    }
+                }
+                encode(&shared.session.frame_message(frame))
+            } else if needs_full_textures {
+                full_textures_packet
+                    .get_or_insert_with(|| {
+                        broadcast(
+                            &shared
+                                .session
+                                .full_textures_delta()
+                                .unwrap_or_else(|| frame.textures_delta.clone()),
+                        )
+                    })
+                    .clone()
+            } else {
+                packet
+                    .get_or_insert_with(|| broadcast(&frame.textures_delta))
+                    .clone()
+            };
+
+            if let Some(packet) = packet {
+                client.send_packet(&packet);
+            }
+        }
+    }
+
+    /// Start or stop watching another session.
+    fn spectate(&mut self, addr: SocketAddr, target: Option<ClientId>) {
+        if target.is_some() {
+            let refusal = if self.shared.is_some() {
+                Some("Spectating is not possible in a shared session")
+            } else if !self.allow_spectators {
+                Some("This server doesn't allow spectating")
+            } else {
+                None
+            };
+            if let Some(refusal) = refusal {
+                if let Some(client) = self.clients.get_mut(&addr) {
+                    client.refuse_spectate(refusal);
+                }
+                return;
+            }
+        }
+
+        let mut refusal = None;
+        let target = target.and_then(|target| {
+            let driver = self
+                .clients
+                .values()
+                .find(|client| client.session.client_id == target && client.tcp_endpoint.is_some());
+            match driver {
+                // Watching a spectator means watching what they watch:
+                Some(driver) => Some(driver.spectating.unwrap_or(target)),
+                None => {
+                    refusal = Some(format!("Can't spectate {}: no such client", target));
+                    None
+                }
+            }
+        });
+        let mirrored = |client_id: ClientId| {
+            self.clients.values().any(|client| {
+                client.session.client_id == client_id && client.session.has_texture_mirror()
+            })
+        };
+        let own_id = self
+            .clients
+            .get(&addr)
+            .map(|client| client.session.client_id);
+        if let (Some(target), Some(own_id)) = (target, own_id) {
+            if !mirrored(target) || !mirrored(own_id) {
+                refusal = Some(format!(
+                    "Can't spectate {}: connected before spectating was allowed",
+                    target
+                ));
+            }
+        }
+        if let Some(refusal) = refusal {
+            if let Some(client) = self.clients.get_mut(&addr) {
+                client.refuse_spectate(&refusal);
+            }
+            return;
+        }
+
+        let client = match self.clients.get_mut(&addr) {
+            Some(client) => client,
+            None => return,
+        };
+        let own_id = client.session.client_id;
+        if target == Some(own_id) || target == client.spectating {
+            return;
+        }
+
+        match target {
+            Some(target) => tracing::info!("{} is now spectating {}", client.info(), target),
+            None => tracing::info!("{} stopped spectating", client.info()),
+        }
+        client.spectating = target;
+        client.needs_full_textures = true;
+        if target.is_none() {
+            client.session.request_frame();
+        }
+
+        for client in self.clients.values_mut() {
+            if Some(client.session.client_id) == target {
+                client.session.request_frame(); // so the new spectator doesn't have to wait
+            }
+            if client.spectating == Some(own_id) {
+                // Follow along to what our driver is now watching:
+                client.spectating = target;
+                client.needs_full_textures = true;
+            }
+        }
+    }
+
+    /// Let one of our spectators drive the session we have been driving.
+    ///
+    /// In a shared session: pass the pointer to another client.
+    fn hand_off(&mut self, addr: SocketAddr, to: ClientId) {
+        if let Some(shared) = &mut self.shared {
+            let from = self
+                .clients
+                .get(&addr)
+                .map(|client| client.session.client_id);
+            let to_operator = self.clients.values().any(|client| {
+                client.session.client_id == to
+                    && client.tcp_endpoint.is_some()
+                    && client.session.role == Role::Operator
+            });
+            if from.is_some() && shared.active == from && to_operator {
+                tracing::info!("Client {} handed the pointer to {}", addr, to);
+                shared.active = Some(to);
+                shared.last_active_event = Instant::now();
+                if let Some(from) = from {
+                    shared.buttons_down.remove(&from);
+                }
+            }
+            return;
+        }
+
+        let driver_id = match self.clients.get(&addr) {
+            Some(driver) if driver.spectating.is_none() => driver.session.client_id,
+            _ => return,
+        };
+        let spectator_addr = self.clients.iter().find_map(|(spectator_addr, client)| {
+            (client.session.client_id == to
+                && client.spectating == Some(driver_id)
+                && client.tcp_endpoint.is_some())
+            .then_some(*spectator_addr)
+        });
+        let spectator_addr = match spectator_addr {
+            Some(spectator_addr) => spectator_addr,
+            None => {
+                tracing::warn!(
+                    "Client {} can't hand off to {}: not one of its spectators",
+                    driver_id,
+                    to
+                );
+                return;
+            }
+        };
+
+        if let Some(mut spectator) = self.clients.remove(&spectator_addr) {
+            if let Some(driver) = self.clients.get_mut(&addr) {
+                driver.session.hand_off(&mut spectator.session);
+                driver.spectating = Some(spectator.session.client_id);
+                spectator.spectating = None;
+                spectator.session.request_frame();
+                tracing::info!("{} handed off to {}", driver.info(), spectator.info());
+            }
+            self.clients.insert(spectator_addr, spectator);
+        }
+    }
+
+    /// Apply changes to the access list to the clients that are already connected.
+    fn disconnect_denied_clients(&mut self) {
+        for client in self.clients.values_mut() {
+            if client.tcp_endpoint.is_some() && !self.access_list.is_allowed(client.addr.ip()) {
+                tracing::warn!("{} is no longer allowed by the access list", client.info());
+                client.disconnect();
+            }
+        }
+    }
+
+    /// Drop the sessions of clients that have disconnected.
+    ///
+    /// A client that reconnects comes from a new address, so it would never get them back.
+    fn remove_disconnected_clients(&mut self) {
+        self.clients.retain(|_, client| {
+            if client.tcp_endpoint.is_none() {
+                tracing::debug!("Removing {}", client.info());
+            }
+            client.tcp_endpoint.is_some()
+        });
+    }
+
+    /// When a driver disconnects, their spectators go back to their own sessions.
+    fn detach_orphaned_spectators(&mut self) {
+        let drivers: HashSet<ClientId> = self
+            .clients
+            .values()
+            .filter(|client| client.tcp_endpoint.is_some() && client.spectating.is_none())
+            .map(|client| client.session.client_id)
+            .collect();
+        for client in self.clients.values_mut() {
+            if let Some(target) = client.spectating {
+                if !drivers.contains(&target) {
+                    client.spectating = None;
+                    client.needs_full_textures = true;
+                    client.session.request_frame();
+                }
+            }
+        }
+    }
+
+    /// Tell clients about changes to their sessions and spectators.
+    fn send_session_infos(&mut self) {
+        let mut spectators: HashMap<ClientId, Vec<ClientId>> = HashMap::new();
+        for client in self.clients.values() {
+            if let (Some(target), Some(_)) = (client.spectating, &client.tcp_endpoint) {
+                spectators
+                    .entry(target)
+                    .or_default()
+                    .push(client.session.client_id);
+            }
+        }
+
+        for client in self.clients.values_mut() {
+            if client.tcp_endpoint.is_none() {
+                continue;
+            }
+            let mut session_info = SessionInfo {
+                client_id: client.session.client_id,
+                spectating: client.spectating,
+                spectators: spectators
+                    .remove(&client.session.client_id)
+                    .unwrap_or_default(),
+            };
+            session_info.spectators.sort_by_key(|client_id| client_id.0);
+            if client.session_info.as_ref() != Some(&session_info) {
+                client.session_info = Some(session_info.clone());
+                client.send_message(&ServerToClientMessage::SessionInfo(session_info));
+            }
+        }
+    }
+
     /// non-blocking
     fn accept_new_clients(&mut self) -> anyhow::Result<()> {
         loop {
             match self.tcp_listener.accept() {
                 Ok((tcp_stream, client_addr)) => {
+                    if !self.access_list.is_allowed(client_addr.ip()) {
+                        tracing::warn!(
+                            "Rejected connection from {}: not allowed by the access list",
+                            client_addr
+                        );
+                        continue; // drops and closes the connection
+                    }
+
                     tcp_stream
                         .set_nonblocking(true)
                         .context("stream.set_nonblocking")?;
-                    let tcp_endpoint = crate::TcpEndpoint { tcp_stream };
+                    let tcp_endpoint = crate::TcpEndpoint::new(tcp_stream);
 
-                    // reuse existing client - especially the egui context
-                    // which contains things like window positons:
                     let clients = &mut self.clients;
                     let next_client_id = &mut self.next_client_id;
+                    let input_mode = self.input_mode;
+                    let input_limits = &self.input_limits;
+                    let allow_spectators = self.allow_spectators;
                     let client = clients.entry(client_addr).or_insert_with(|| {
                         let client_id = ClientId(*next_client_id);
                         *next_client_id += 1;
 
+                        let mut session = Session::new(client_id);
+                        session.input_mode = input_mode;
+                        session.input_limits = input_limits.clone();
+                        if allow_spectators {
+                            session.mirror_textures();
+                        }
+
                         Client {
-                            client_id,
                             addr: client_addr,
                             tcp_endpoint: None,
-                            start_time: std::time::Instant::now(),
-                            frame_index: 0,
-                            egui_ctx: Default::default(),
-                            new_input: None,
-                            //prev_input: None,
-                            last_client_time: None,
-                            last_update: Instant::now() - DEFAULT_MIN_UPDATE_INTERVAL,
+                            rate_limiter: None,
+                            session,
+                            spectating: None,
+                            needs_full_textures: false,
+                            session_info: None,
                             last_visuals: Default::default(),
-                            max_update_interval: DEFAULT_MAX_UPDATE_INTERVAL,
                         }
                     });
 
                     client.tcp_endpoint = Some(tcp_endpoint);
+                    client.needs_full_textures = true;
+                    client.session.reset_input();
+                    client.session.role = (self.role_fn)(client_addr);
+                    client.session.audit = self.audit_log.clone().map(|audit_log| {
+                        SessionAudit::new(audit_log, client.session.client_id.0, client_addr)
+                    });
+                    if let Some(audit) = &mut client.session.audit {
+                        audit.connected();
+                    }
+                    client.rate_limiter = self
+                        .rate_limit
+                        .clone()
+                        .map(|rate_limit| RateLimiter::new(rate_limit, client.info()));
 
-                    tracing::info!("{} connected", client.info());
+                    tracing::info!("{} connected as {:?}", client.info(), client.session.role);
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     break; // No (more) new clients
@@ -119,8 +988,24 @@ impl Server {
 
     /// non-blocking
     fn try_receive(&mut self) {
-        for client in self.clients.values_mut() {
-            client.try_receive();
+        let mut requests = vec![];
+        for (addr, client) in &mut self.clients {
+            for request in client.try_receive() {
+                requests.push((*addr, request));
+            }
+        }
+        for (addr, request) in requests {
+            match request {
+                ControlFlow::Spectate(target) => self.spectate(addr, target),
+                ControlFlow::HandOff(to) => self.hand_off(addr, to),
+                ControlFlow::Custom(message) => {
+                    if let Some(client) = self.clients.get(&addr) {
+                        self.custom_handlers
+                            .handle(client.session.client_id, &message);
+                    }
+                }
+                ControlFlow::Continue | ControlFlow::Disconnect => {}
+            }
         }
     }
 }
@@ -128,102 +1013,170 @@ impl Server {
 // ----------------------------------------------------------------------------
 
 struct Client {
-    client_id: ClientId,
     addr: SocketAddr,
     tcp_endpoint: Option<crate::TcpEndpoint>,
-    start_time: std::time::Instant,
-    frame_index: u64,
-    egui_ctx: egui::Context,
-    /// Set when there is something to do. Cleared after painting.
-    new_input: Option<egui::RawInput>,
-    /// The client's time of the last input.
-    last_client_time: Option<f64>,
-    last_update: std::time::Instant,
+    rate_limiter: Option<RateLimiter>,
+    session: Session,
+    /// The session we are watching instead of our own.
+    spectating: Option<ClientId>,
+    /// The client has textures from another session (or none at all).
+    needs_full_textures: bool,
+    /// What we last told the client.
+    session_info: Option<SessionInfo>,
     last_visuals: Vec<ClippedNetMesh>,
-    max_update_interval: Duration,
+}
+
+/// A frame of a session, for sending to its spectators.
+struct SpectatedFrame {
+    frame_index: u64,
+    clipped_net_mesh: Vec<ClippedNetMesh>,
+    textures_delta: egui::TexturesDelta,
+    /// Set if any spectator needs it.
+    full_textures_delta: Option<egui::TexturesDelta>,
+    /// What the frame was laid out for.
+    screen_rect: Option<egui::Rect>,
 }
 
 impl Client {
     fn disconnect(&mut self) {
         self.tcp_endpoint = None;
+        self.session.transfers.clear();
         self.last_visuals = Default::default();
+        self.session_info = None;
+        if let Some(mut audit) = self.session.audit.take() {
+            audit.disconnected();
+        }
     }
 
-    // Show is called from the app's main loop (e.g. 60 time per sec),
-    // but new frames are only build and send to the eterm client every
-    // Client.max_update_interval or less when there is no new input.
-    // Input sent by the client is continously collected in the backgound
-    // and kept in Client.new_input. No input is lost, even if the
-    // max_update_interval is set to a high number.
+    /// Returns the frame if there is one and `spectated` is set,
+    /// in which case it says if a spectator needs all the textures.
     fn show(
         &mut self,
         do_ui: &mut dyn FnMut(&egui::Context, ClientId),
         minimum_update_interval: Duration,
-    ) {
+        spectated: Option<bool>,
+        pointers: &[RemotePointer],
+    ) -> Option<SpectatedFrame> {
         // Don't do anything if there is no client
-        if self.tcp_endpoint.is_none() {
-            return;
+        self.tcp_endpoint.as_ref()?;
+
+        if !self.session.wants_frame(minimum_update_interval) {
+            return None;
         }
 
-        let minimum_interval_has_passed = self.last_update.elapsed() >= minimum_update_interval;
-        let input_triggered_update =
-            self.new_input.is_some() && self.last_update.elapsed() >= self.max_update_interval;
+        let mut frame = self.session.create_frame(&mut |egui_ctx, client_id| {
+            do_ui(egui_ctx, client_id);
+            if !pointers.is_empty() {
+                paint_remote_pointers(egui_ctx, pointers, Some(client_id));
+            }
+        });
+        let spectated_frame = spectated.map(|needs_full_textures| SpectatedFrame {
+            frame_index: frame.frame_index,
+            clipped_net_mesh: frame.clipped_net_mesh.clone(),
+            textures_delta: frame.textures_delta.clone(),
+            full_textures_delta: if needs_full_textures {
+                self.session.full_textures_delta()
+            } else {
+                None
+            },
+            screen_rect: self.session.screen_rect(),
+        });
 
-        if minimum_interval_has_passed || input_triggered_update {
-            let message = self.create_frame(do_ui);
-            self.send_message(&message);
+        if std::mem::take(&mut self.needs_full_textures) {
+            // Without a mirror, the client still has our textures from before:
+            if let Some(full_textures_delta) = self.session.full_textures_delta() {
+                frame.textures_delta = full_textures_delta;
+            }
         }
+        let message = self.session.frame_message(frame);
+        self.send_message(&message);
+        spectated_frame
     }
 
-    // Create a frame for the client
-    fn create_frame(
-        &mut self,
-        do_ui: &mut dyn FnMut(&egui::Context, ClientId),
-    ) -> ServerToClientMessage {
-        // Reset instant of last update
-        self.last_update = Instant::now();
-
-        // Take accumulated input
-        let mut input = self.new_input.take().unwrap_or_default();
-
-        // Override client time with server time
-        input.time = Some(self.start_time.elapsed().as_secs_f64());
+    /// Tell the client why it can't spectate, and that it still has its own session.
+    fn refuse_spectate(&mut self, reason: &str) {
+        tracing::warn!("{}: {}", self.info(), reason);
+        self.session
+            .notifications
+            .push(crate::Notification::warning(reason).with_duration(Duration::from_secs(10)));
+        self.session.request_repaint();
+        self.session_info = None; // send it again, as a reply
+    }
 
-        // Refresh egui
-        let full_output = self
-            .egui_ctx
-            .run(input, |egui_ctx| do_ui(egui_ctx, self.client_id));
+    /// Send a frame of the session we are watching, scaled down if our screen is smaller.
+    fn show_spectated(&mut self, frame: &SpectatedFrame) {
+        if self.tcp_endpoint.is_none() {
+            return;
+        }
 
-        // tesselate shapes
-        let clipped_primitives = self.egui_ctx.tessellate(full_output.clone().shapes);
-        let clipped_net_mesh = into_clipped_net_meshes(clipped_primitives);
-        let textures_delta = full_output.textures_delta.clone();
+        let mut clipped_net_mesh = frame.clipped_net_mesh.clone();
+        if let (Some(theirs), Some(ours)) = (frame.screen_rect, self.session.screen_rect()) {
+            let scale = (ours.width() / theirs.width())
+                .min(ours.height() / theirs.height())
+                .min(1.0);
+            if scale.is_finite() && 0.0 < scale && scale < 1.0 {
+                for clipped_mesh in &mut clipped_net_mesh {
+                    clipped_mesh.scale(scale);
+                }
+            }
+        }
 
-        // Prepare a new frame for the client
-        let frame_index = self.frame_index;
-        self.frame_index += 1;
+        let textures_delta = match &frame.full_textures_delta {
+            Some(full_textures_delta) if self.needs_full_textures => full_textures_delta.clone(),
+            _ => frame.textures_delta.clone(),
+        };
+        self.needs_full_textures = false;
 
-        crate::ServerToClientMessage::Frame {
-            frame_index,
-            platform_output: full_output.platform_output,
+        // The platform output (copied text, opened urls, …) is only for the driver.
+        let message = ServerToClientMessage::Frame {
+            frame_index: frame.frame_index,
+            platform_output: Default::default(),
             clipped_net_mesh,
+            client_time: None,
             textures_delta,
-            client_time: self.last_client_time.take(),
-        }
+        };
+        self.send_message(&message);
     }
 
     fn info(&self) -> String {
-        format!("Client {} ({})", self.client_id.0, self.addr)
+        format!("Client {} ({})", self.session.client_id.0, self.addr)
     }
 
-    fn send_message(&mut self, message: &impl serde::Serialize) {
+    /// Send the next few chunks of the downloads, and any replies about uploads.
+    fn send_transfer_messages(&mut self) {
+        for _ in 0..CHUNKS_PER_TICK {
+            if self.tcp_endpoint.is_none() {
+                return;
+            }
+            match self.session.transfers.next_message() {
+                Some(message) => self.send_message(&ServerToClientMessage::Transfer(message)),
+                None => return,
+            }
+        }
+    }
+
+    fn send_packet(&mut self, packet: &[u8]) {
+        if let Some(tcp_endpoint) = &mut self.tcp_endpoint {
+            if let Err(err) = tcp_endpoint.send_packet(packet) {
+                tracing::error!(
+                    "Failed to send to client {:?} {}: {:?}. Disconnecting.",
+                    self.session.client_id,
+                    self.addr,
+                    crate::error_display_chain(err.as_ref())
+                );
+                self.disconnect();
+            }
+        }
+    }
+
+    fn send_message(&mut self, message: &ServerToClientMessage) {
         if let Some(tcp_endpoint) = &mut self.tcp_endpoint {
-            match tcp_endpoint.send_message(&message) {
+            match tcp_endpoint.send_message(message) {
                 Ok(()) => {}
                 Err(err) => {
                     tracing::error!(
                         "Failed to send to client {:?} {}: {:?}. Disconnecting.",
-                        self.client_id,
+                        self.session.client_id,
                         self.addr,
                         crate::error_display_chain(err.as_ref())
                     );
@@ -233,17 +1186,33 @@ impl Client {
         }
     }
 
-    /// non-blocking
-    fn try_receive(&mut self) {
+    /// non-blocking.
+    ///
+    /// Returns requests that involve other clients.
+    fn try_receive(&mut self) -> Vec<ControlFlow> {
+        let mut requests = vec![];
         loop {
             let tcp_endpoint = match &mut self.tcp_endpoint {
                 Some(tcp_endpoint) => tcp_endpoint,
-                None => return,
+                None => return requests,
             };
 
-            let message = match tcp_endpoint.try_receive_message() {
+            if let Some(rate_limiter) = &mut self.rate_limiter {
+                match rate_limiter.check(Instant::now()) {
+                    RateLimitVerdict::Allow => {}
+                    RateLimitVerdict::Throttle(_) => {
+                        return requests; // leave the rest on the socket for now
+                    }
+                    RateLimitVerdict::Disconnect => {
+                        self.disconnect();
+                        return requests;
+                    }
+                }
+            }
+
+            let message = match receive_message(tcp_endpoint, self.rate_limiter.as_mut()) {
                 Ok(None) => {
-                    return;
+                    return requests;
                 }
                 Ok(Some(message)) => message,
                 Err(err) => {
@@ -253,39 +1222,1452 @@ impl Client {
                         crate::error_display_chain(err.as_ref())
                     );
                     self.disconnect();
-                    return;
+                    return requests;
                 }
             };
 
-            match message {
-                ClientToServerMessage::Input {
-                    raw_input,
-                    client_time,
-                    //points_per_pixel,
-                } => {
-                    //eprintln!("{:?}", raw_input);
-                    self.append_input(raw_input);
-                    self.last_client_time = Some(client_time);
-                    //self.points_per_pixel = points_per_pixel;
+            match self.session.on_message(message) {
+                ControlFlow::Continue => {
                     // keep polling for more messages
                 }
-                ClientToServerMessage::Goodbye => {
+                ControlFlow::Disconnect => {
                     self.disconnect();
-                    return;
+                    return requests;
+                }
+                request @ (ControlFlow::Spectate(_)
+                | ControlFlow::HandOff(_)
+                | ControlFlow::Custom(_)) => {
+                    requests.push(request);
+                }
+            }
+        }
+    }
+}
+
+/// non-blocking
+fn receive_message(
+    tcp_endpoint: &mut crate::TcpEndpoint,
+    rate_limiter: Option<&mut RateLimiter>,
+) -> anyhow::Result<Option<ClientToServerMessage>> {
+    match tcp_endpoint.try_receive_packet().context("receive")? {
+        Some(packet) => {
+            let message = crate::decode_message(packet).context("decode")?;
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.consume_message(packet.len(), &message);
+            }
+            Ok(Some(message))
+        }
+        None => Ok(None),
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// One egui context used by all clients together, see [`Server::set_shared_session`].
+struct SharedSession {
+    session: Session,
+    /// The client (by the id of its own session) whose input is fed to the shared context.
+    active: Option<ClientId>,
+    /// When the active client last sent an event.
+    last_active_event: Instant,
+    /// Clients holding down a pointer button.
+    buttons_down: HashSet<ClientId>,
+    /// What all clients fit in.
+    screen_rect: Option<egui::Rect>,
+}
+
+impl SharedSession {
+    fn new(session: Session) -> Self {
+        Self {
+            session,
+            active: None,
+            last_active_event: Instant::now(),
+            buttons_down: Default::default(),
+            screen_rect: None,
+        }
+    }
+
+    /// Remember which buttons this client is holding down.
+    /// Returns `true` if there were any events.
+    fn track_buttons(&mut self, id: ClientId, inputs: &[RawInput]) -> bool {
+        let mut has_events = false;
+        for event in inputs.iter().flat_map(|input| &input.events) {
+            has_events = true;
+            match event {
+                egui::Event::PointerGone => {
+                    self.buttons_down.remove(&id);
+                }
+                egui::Event::PointerButton { pressed, .. } => {
+                    if *pressed {
+                        self.buttons_down.insert(id);
+                    } else {
+                        self.buttons_down.remove(&id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        has_events
+    }
+
+    /// Never in the middle of a drag, and not while the active client is busy.
+    fn can_take_over(&self, id: ClientId, now: Instant) -> bool {
+        match self.active {
+            None => true,
+            Some(active) => {
+                active != id
+                    && !self.buttons_down.contains(&active)
+                    && now.duration_since(self.last_active_event) >= SHARED_POINTER_IDLE_TIMEOUT
+            }
+        }
+    }
+}
+
+/// Where each connected client is pointing.
+fn remote_pointers(clients: &HashMap<SocketAddr, Client>) -> Vec<RemotePointer> {
+    clients
+        .values()
+        .filter(|client| client.tcp_endpoint.is_some())
+        .filter_map(|client| {
+            Some(RemotePointer {
+                client_id: client.session.client_id,
+                pos: client.session.pointer?,
+                name: client.session.name.clone(),
+            })
+        })
+        .collect()
+}
+
+// ----------------------------------------------------------------------------
+
+/// What to do with the connection after handling a message.
+pub(crate) enum ControlFlow {
+    Continue,
+    Disconnect,
+    /// The client wants to watch this session (or its own, if `None`).
+    Spectate(Option<ClientId>),
+    /// The client wants to give control of its session to this spectator.
+    HandOff(ClientId),
+    /// For the application.
+    Custom(CustomMessage),
+}
+
+/// The egui side of a client: its [`egui::Context`] and the input it has sent us.
+///
+/// Shared by the blocking and async servers.
+pub(crate) struct Session {
+    pub(crate) client_id: ClientId,
+    frame_index: u64,
+    egui_ctx: egui::Context,
+    pub(crate) input_mode: InputMode,
+    pub(crate) input_limits: InputLimits,
+    pub(crate) audit: Option<SessionAudit>,
+    pub(crate) role: Role,
+    /// The input state (screen size, focus, …) the client has told us about so far.
+    input_state: egui::RawInput,
+    /// Input batches received since the last frame, oldest first.
+    /// Non-empty when there is something to do. Cleared after painting.
+    new_input: Vec<egui::RawInput>,
+    /// The client's time of the last input.
+    last_client_time: Option<f64>,
+    /// Translates the client's clock into ours.
+    clock_offset: ClockOffset,
+    /// The last time we gave to egui, in [`server_time`].
+    last_egui_time: f64,
+    last_update: std::time::Instant,
+    max_update_interval: Duration,
+    /// Send a frame right away, e.g. because a spectator joined.
+    frame_requested: bool,
+    /// Send a frame soon, as if we had new input.
+    repaint_requested: bool,
+    /// Shown on top of the ui.
+    pub(crate) notifications: Notifications,
+    /// What the client wants to be called by others.
+    pub(crate) name: Option<String>,
+    /// What the client told us about itself.
+    pub(crate) capabilities: Capabilities,
+    /// Where the client is pointing, if anywhere.
+    pub(crate) pointer: Option<egui::Pos2>,
+    /// Has `pointer` changed since [`Self::take_pointer_moved`]?
+    pointer_moved: bool,
+    /// Everything we have sent to the client, for bringing spectators up to date.
+    /// Only kept if spectating is allowed, since it costs as much memory as the textures.
+    textures: Option<TextureMirror>,
+    /// Uploads from the client, and downloads to it.
+    pub(crate) transfers: Transfers,
+}
+
+impl Session {
+    pub(crate) fn new(client_id: ClientId) -> Self {
+        Self {
+            client_id,
+            frame_index: 0,
+            egui_ctx: Default::default(),
+            input_mode: Default::default(),
+            input_limits: Default::default(),
+            audit: None,
+            role: Default::default(),
+            input_state: Default::default(),
+            new_input: Default::default(),
+            last_client_time: None,
+            clock_offset: Default::default(),
+            last_egui_time: 0.0,
+            last_update: Instant::now() - DEFAULT_MIN_UPDATE_INTERVAL,
+            max_update_interval: DEFAULT_MAX_UPDATE_INTERVAL,
+            frame_requested: false,
+            repaint_requested: false,
+            notifications: Default::default(),
+            name: None,
+            capabilities: Default::default(),
+            pointer: None,
+            pointer_moved: false,
+            textures: None,
+            transfers: Transfers::new(Direction::Download, 0),
+        }
+    }
+
+    /// Swap the egui side of two sessions, keeping what belongs to each connection
+    /// (input state, role, limits, clock, audit).
+    pub(crate) fn hand_off(&mut self, other: &mut Session) {
+        std::mem::swap(&mut self.client_id, &mut other.client_id);
+        std::mem::swap(&mut self.frame_index, &mut other.frame_index);
+        std::mem::swap(&mut self.egui_ctx, &mut other.egui_ctx);
+        std::mem::swap(&mut self.new_input, &mut other.new_input);
+        std::mem::swap(&mut self.last_egui_time, &mut other.last_egui_time);
+        std::mem::swap(&mut self.last_update, &mut other.last_update);
+        std::mem::swap(&mut self.textures, &mut other.textures);
+        std::mem::swap(&mut self.notifications, &mut other.notifications);
+
+        for session in [self, other] {
+            if let Some(audit) = &mut session.audit {
+                audit.set_client_id(session.client_id.0);
+            }
+        }
+    }
+
+    /// The screen size the client has told us about, in points.
+    pub(crate) fn screen_rect(&self) -> Option<egui::Rect> {
+        self.input_state.screen_rect
+    }
+
+    /// Drop the input we got since the last frame, e.g. because we are spectating.
+    pub(crate) fn discard_input(&mut self) {
+        self.new_input.clear();
+        self.last_client_time = None;
+    }
+
+    /// Forget the input state of the previous connection,
+    /// since a new connection encodes its input deltas from scratch.
+    pub(crate) fn reset_input(&mut self) {
+        self.discard_input();
+        self.input_state = Default::default();
+        if self.pointer.take().is_some() {
+            self.pointer_moved = true;
+        }
+    }
+
+    /// Send a frame as soon as possible.
+    pub(crate) fn request_frame(&mut self) {
+        self.frame_requested = true;
+    }
+
+    /// Send a frame soon, as if there was new input (e.g. because someone else's pointer moved).
+    pub(crate) fn request_repaint(&mut self) {
+        self.repaint_requested = true;
+    }
+
+    /// Has the pointer moved since last time we asked?
+    pub(crate) fn take_pointer_moved(&mut self) -> bool {
+        std::mem::take(&mut self.pointer_moved)
+    }
+
+    /// Keep a copy of all textures from now on, for [`Self::full_textures_delta`].
+    ///
+    /// Call before the first frame.
+    pub(crate) fn mirror_textures(&mut self) {
+        self.textures.get_or_insert_with(Default::default);
+    }
+
+    pub(crate) fn has_texture_mirror(&self) -> bool {
+        self.textures.is_some()
+    }
+
+    /// All textures the client should have as one delta, if we keep a copy of them.
+    pub(crate) fn full_textures_delta(&self) -> Option<egui::TexturesDelta> {
+        self.textures.as_ref().map(TextureMirror::full_delta)
+    }
+
+    /// Start over with a new egui context, which sends all its textures again.
+    pub(crate) fn reset_context(&mut self) {
+        self.egui_ctx = Default::default();
+    }
+
+    pub(crate) fn on_message(&mut self, message: ClientToServerMessage) -> ControlFlow {
+        match message {
+            ClientToServerMessage::Input {
+                mut input,
+                client_time,
+            } => {
+                let mut violations = self.input_limits.sanitize(&mut input);
+
+                // The input waiting for the next frame is limited too
+                // (an empty batch still costs an egui pass in lossless mode):
+                let queued: usize = self
+                    .new_input
+                    .iter()
+                    .map(|input| input.events.len().max(1))
+                    .sum();
+                let room = self.input_limits.max_events.saturating_sub(queued);
+                if input.events.len().max(1) > room {
+                    violations.push(format!(
+                        "Too much input waiting for the next frame: {} events",
+                        queued + input.events.len()
+                    ));
+                    input.events.truncate(room);
+                }
+
+                if !violations.is_empty() {
+                    let policy = self.input_limits.policy;
+                    for violation in &violations {
+                        tracing::warn!(
+                            "Client {} sent bad input: {}. Policy: {:?}",
+                            self.client_id.0,
+                            violation,
+                            policy
+                        );
+                    }
+                    match policy {
+                        ViolationPolicy::Clamp => {}
+                        ViolationPolicy::Ignore => return ControlFlow::Continue,
+                        ViolationPolicy::Disconnect => return ControlFlow::Disconnect,
+                    }
+                }
+
+                // Viewers can't click the ui, but may dismiss their notifications:
+                if self.role != Role::Operator && self.notifications.dismiss_clicked(&input.events)
+                {
+                    self.request_repaint();
+                }
+                self.role.filter_input(&mut input);
+                self.track_pointer(&input.events);
+
+                let mut raw_input = input.decode(&mut self.input_state);
+                raw_input.time = Some(self.client_time_to_server_time(client_time));
+                match self.new_input.last_mut() {
+                    // Keep the state changes without adding a batch:
+                    Some(last) if room == 0 => last.append(raw_input),
+                    _ => self.append_input(raw_input),
+                }
+                self.last_client_time = Some(client_time);
+                ControlFlow::Continue
+            }
+            ClientToServerMessage::Goodbye => ControlFlow::Disconnect,
+            ClientToServerMessage::Spectate { client_id } => ControlFlow::Spectate(client_id),
+            ClientToServerMessage::HandOff { to } => ControlFlow::HandOff(to),
+            ClientToServerMessage::Hello(mut capabilities) => {
+                capabilities.sanitize();
+                let sides = &self.input_limits.max_texture_side;
+                capabilities.max_texture_side = capabilities
+                    .max_texture_side
+                    .map(|side| side.clamp(*sides.start(), *sides.end()));
+                tracing::info!(
+                    "Client {} is {} {} on {:?}",
+                    self.client_id.0,
+                    capabilities.viewer_name,
+                    capabilities.viewer_version,
+                    capabilities.os
+                );
+                self.capabilities = capabilities;
+                self.request_repaint();
+                ControlFlow::Continue
+            }
+            ClientToServerMessage::SetName { name } => {
+                self.name = crate::presence::sanitize_name(&name);
+                tracing::info!("Client {} is called {:?}", self.client_id.0, self.name);
+                ControlFlow::Continue
+            }
+            ClientToServerMessage::Custom(message) => {
+                if self.role == Role::Operator {
+                    ControlFlow::Custom(message)
+                } else {
+                    tracing::debug!(
+                        "Dropped custom message from {:?} client {} on channel {:?}",
+                        self.role,
+                        self.client_id.0,
+                        message.channel
+                    );
+                    ControlFlow::Continue
+                }
+            }
+            ClientToServerMessage::Transfer(message) => {
+                self.on_transfer_message(message);
+                ControlFlow::Continue
+            }
+        }
+    }
+
+    fn on_transfer_message(&mut self, message: TransferMessage) {
+        if let TransferMessage::Start { id, .. } = &message {
+            if self.role != Role::Operator {
+                self.transfers
+                    .refuse(*id, format!("A {:?} may not upload files", self.role));
+                return;
+            }
+        }
+
+        self.transfers
+            .set_max_incoming_size(self.input_limits.max_dropped_file_size as u64);
+        self.transfers.on_message(message);
+
+        let dropped_files: Vec<egui::DroppedFile> = self
+            .transfers
+            .take_received()
+            .into_iter()
+            .map(|file| file.into_dropped_file())
+            .collect();
+        if !dropped_files.is_empty() {
+            self.append_input(RawInput {
+                dropped_files,
+                ..self.input_state.clone()
+            });
+        }
+    }
+
+    fn track_pointer(&mut self, events: &[egui::Event]) {
+        for event in events {
+            match event {
+                egui::Event::PointerMoved(pos) => {
+                    self.pointer = Some(*pos);
+                    self.pointer_moved = true;
+                }
+                egui::Event::PointerGone => {
+                    self.pointer = None;
+                    self.pointer_moved = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Frames are only built and sent to the eterm client every
+    // max_update_interval or less when there is no new input.
+    // Input sent by the client is continously collected in the backgound
+    // and kept in Session.new_input. No input is lost, even if the
+    // max_update_interval is set to a high number.
+    // How that input is fed to egui depends on the InputMode.
+    pub(crate) fn wants_frame(&self, minimum_update_interval: Duration) -> bool {
+        Instant::now() >= self.next_frame_time(minimum_update_interval)
+    }
+
+    /// When should we next send a frame, given the input we have so far?
+    pub(crate) fn next_frame_time(&self, minimum_update_interval: Duration) -> Instant {
+        let next_frame_time = if self.frame_requested {
+            self.last_update
+        } else if !self.new_input.is_empty() || self.repaint_requested {
+            self.last_update + self.max_update_interval.min(minimum_update_interval)
+        } else {
+            self.last_update + minimum_update_interval
+        };
+
+        // Remove expired notifications on time:
+        match self.notifications.next_expiry() {
+            Some(expiry) => next_frame_time.min(expiry),
+            None => next_frame_time,
+        }
+    }
+
+    // Create a frame for the client
+    pub(crate) fn create_frame(
+        &mut self,
+        do_ui: &mut dyn FnMut(&egui::Context, ClientId),
+    ) -> EtermFrame {
+        // Reset instant of last update
+        self.last_update = Instant::now();
+        self.frame_requested = false;
+        self.repaint_requested = false;
+
+        // Take accumulated input
+        let mut inputs = std::mem::take(&mut self.new_input);
+        if let InputMode::Merged = self.input_mode {
+            inputs = merge_inputs(inputs);
+        }
+        if inputs.is_empty() {
+            inputs.push(Default::default());
+        }
+
+        // Refresh egui, once per input batch
+        let mut full_output = egui::FullOutput::default();
+        for mut input in inputs {
+            // Use the time the input happened on the client, translated to our clock,
+            // so that egui sees realistic timing for double-clicks, drag velocities etc.
+            // Without input we use the current time.
+            // Either way, time must never go backwards:
+            let time = input
+                .time
+                .unwrap_or_else(server_time)
+                .max(self.last_egui_time);
+            self.last_egui_time = time;
+            input.time = Some(time);
+            if input.max_texture_side.is_none() {
+                input.max_texture_side = self.capabilities.max_texture_side;
+            }
+
+            let input_events = if self.audit.is_some() {
+                input.events.clone()
+            } else {
+                vec![]
+            };
+
+            let role = self.role;
+            let capabilities = &self.capabilities;
+            let transfers = self.transfers.progress();
+            let output = self.egui_ctx.run(input, |egui_ctx| {
+                role.set(egui_ctx);
+                capabilities.set(egui_ctx);
+                crate::transfer::set_progress(egui_ctx, transfers.clone());
+                do_ui(egui_ctx, self.client_id);
+                self.notifications.show(egui_ctx);
+            });
+
+            if let Some(audit) = &mut self.audit {
+                audit.on_pass(&input_events, &output.platform_output);
+            }
+
+            match self.input_mode {
+                InputMode::Lossless {
+                    keep_intermediate_output: false,
+                } => {
+                    // Texture updates can never be skipped:
+                    let mut textures_delta = std::mem::take(&mut full_output.textures_delta);
+                    textures_delta.append(output.textures_delta);
+                    full_output = egui::FullOutput {
+                        textures_delta,
+                        ..output
+                    };
+                }
+                InputMode::Merged
+                | InputMode::Lossless {
+                    keep_intermediate_output: true,
+                } => {
+                    full_output.append(output);
                 }
             }
         }
+
+        if self.notifications.take_needs_repaint() {
+            self.repaint_requested = true; // show that a notification was dismissed
+        }
+
+        // tesselate shapes
+        let clipped_primitives = self.egui_ctx.tessellate(full_output.clone().shapes);
+        let clipped_net_mesh = into_clipped_net_meshes(clipped_primitives);
+        let textures_delta = full_output.textures_delta.clone();
+        if let Some(textures) = &mut self.textures {
+            textures.apply(&textures_delta);
+        }
+
+        // Prepare a new frame for the client
+        let frame_index = self.frame_index;
+        self.frame_index += 1;
+
+        EtermFrame {
+            frame_index,
+            platform_output: full_output.platform_output,
+            clipped_net_mesh,
+            textures_delta,
+        }
+    }
+
+    /// The message for sending a frame of this session to its own client.
+    ///
+    /// Leaves out the clipboard and urls if the client doesn't support them.
+    pub(crate) fn frame_message(&mut self, mut frame: EtermFrame) -> ServerToClientMessage {
+        if !self.capabilities.has_feature("clipboard") {
+            frame.platform_output.copied_text.clear();
+        }
+        if !self.capabilities.has_feature("open_url") {
+            frame.platform_output.open_url = None;
+        }
+        frame_message(frame, self.last_client_time.take())
+    }
+
+    /// The [`server_time`] at which something happened on the client.
+    fn client_time_to_server_time(&mut self, client_time: f64) -> f64 {
+        let server_time = server_time();
+        self.clock_offset.add(server_time, client_time);
+        let translated = client_time + self.clock_offset.offset().unwrap_or(0.0);
+
+        // Clamp so that input never arrives from the future, and never goes backwards:
+        translated.min(server_time).max(self.last_egui_time)
     }
 
     // accumulates input from the client
     fn append_input(&mut self, new_input: RawInput) {
-        match &mut self.new_input {
-            None => {
-                self.new_input = Some(new_input);
+        self.new_input.push(new_input);
+    }
+}
+
+fn frame_message(frame: EtermFrame, client_time: Option<f64>) -> ServerToClientMessage {
+    let EtermFrame {
+        frame_index,
+        platform_output,
+        clipped_net_mesh,
+        textures_delta,
+    } = frame;
+    ServerToClientMessage::Frame {
+        frame_index,
+        platform_output,
+        clipped_net_mesh,
+        client_time,
+        textures_delta,
+    }
+}
+
+/// Seconds on a clock shared by all sessions,
+/// so that a session can be handed over to another connection.
+fn server_time() -> f64 {
+    static START: Mutex<Option<Instant>> = parking_lot::const_mutex(None);
+    START
+        .lock()
+        .get_or_insert_with(Instant::now)
+        .elapsed()
+        .as_secs_f64()
+}
+
+/// Merge input batches, ending a merged batch at each batch with timing-sensitive events.
+///
+/// A merged batch has the time of its last batch,
+/// so this keeps the time of every click and scroll.
+fn merge_inputs(inputs: Vec<RawInput>) -> Vec<RawInput> {
+    let mut merged: Vec<RawInput> = vec![];
+    let mut open = false;
+    for input in inputs {
+        let ends_batch = input.events.iter().any(is_timing_sensitive);
+        match merged.last_mut() {
+            Some(last) if open => last.append(input),
+            _ => merged.push(input),
+        }
+        open = !ends_batch;
+    }
+    merged
+}
+
+/// Events whose time matters, e.g. for double-clicks and kinetic scrolling.
+fn is_timing_sensitive(event: &egui::Event) -> bool {
+    matches!(
+        event,
+        egui::Event::PointerButton { .. } | egui::Event::Scroll(_) | egui::Event::Zoom(_)
+    )
+}
+
+#[test]
+fn test_merged_input_keeps_click_times() {
+    let click = |pressed| egui::Event::PointerButton {
+        pos: egui::pos2(10.0, 10.0),
+        button: egui::PointerButton::Primary,
+        pressed,
+        modifiers: Default::default(),
+    };
+    let batch = |time: f64, events: Vec<egui::Event>| RawInput {
+        time: Some(time),
+        events,
+        ..Default::default()
+    };
+    let moved = || egui::Event::PointerMoved(egui::pos2(10.0, 10.0));
+
+    let merged = merge_inputs(vec![
+        batch(0.0, vec![moved()]),
+        batch(0.1, vec![click(true), click(false)]),
+        batch(0.2, vec![moved()]),
+        batch(0.5, vec![click(true), click(false)]),
+        batch(0.6, vec![moved()]),
+        batch(0.7, vec![moved()]),
+    ]);
+    let times: Vec<_> = merged.iter().map(|input| input.time).collect();
+    let event_counts: Vec<_> = merged.iter().map(|input| input.events.len()).collect();
+    assert_eq!(times, vec![Some(0.1), Some(0.5), Some(0.7)]);
+    assert_eq!(event_counts, vec![3, 3, 2]);
+}
+
+#[test]
+fn test_lossless_input_mode() {
+    let pointer_samples = [egui::pos2(10.0, 10.0), egui::pos2(20.0, 20.0)];
+
+    let run = |input_mode: InputMode| {
+        let mut session = Session::new(ClientId(0));
+        session.input_mode = input_mode;
+        for (i, pos) in pointer_samples.into_iter().enumerate() {
+            session.on_message(ClientToServerMessage::Input {
+                input: crate::messages::InputDelta {
+                    events: vec![egui::Event::PointerMoved(pos)],
+                    ..Default::default()
+                },
+                client_time: i as f64 * 0.01,
+            });
+        }
+
+        let mut seen = vec![];
+        session.create_frame(&mut |egui_ctx, _| {
+            seen.push(egui_ctx.input().pointer.hover_pos());
+        });
+        seen
+    };
+
+    assert_eq!(run(InputMode::Merged), vec![Some(pointer_samples[1])]);
+    assert_eq!(
+        run(InputMode::Lossless {
+            keep_intermediate_output: false
+        }),
+        vec![Some(pointer_samples[0]), Some(pointer_samples[1])]
+    );
+}
+
+/// Connect to the server and tell it how large our screen is.
+#[cfg(test)]
+fn test_connect(server: &Server, screen_size: egui::Vec2) -> crate::TcpEndpoint {
+    let addr = server.tcp_listener.local_addr().unwrap();
+    let tcp_stream = std::net::TcpStream::connect(addr).unwrap();
+    tcp_stream.set_nonblocking(true).unwrap();
+    let mut endpoint = crate::TcpEndpoint::new(tcp_stream);
+    let input = crate::messages::InputDelta {
+        screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, screen_size)),
+        ..Default::default()
+    };
+    endpoint
+        .send_message(&ClientToServerMessage::Input {
+            input,
+            client_time: 0.0,
+        })
+        .unwrap();
+    endpoint
+}
+
+/// Run the server until each endpoint has received a message that `done` accepts.
+#[cfg(test)]
+fn test_run_until(
+    server: &mut Server,
+    do_ui: &mut dyn FnMut(&egui::Context, ClientId),
+    endpoints: &mut [&mut crate::TcpEndpoint],
+    done: &mut dyn FnMut(usize, ServerToClientMessage) -> bool,
+) {
+    let mut satisfied = vec![false; endpoints.len()];
+    let start = Instant::now();
+    while satisfied.contains(&false) {
+        assert!(start.elapsed() < Duration::from_secs(10), "Timeout");
+        server.show_dyn(do_ui).unwrap();
+        for (i, endpoint) in endpoints.iter_mut().enumerate() {
+            while let Some(packet) = endpoint.try_receive_packet().unwrap() {
+                let message = crate::decode_message(packet).unwrap();
+                satisfied[i] |= done(i, message);
             }
-            Some(existing_input) => {
-                existing_input.append(new_input);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_spectate_and_hand_off() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    server.set_allow_spectators(true);
+    let mut ui = |egui_ctx: &egui::Context, _: ClientId| {
+        egui::CentralPanel::default().show(egui_ctx, |ui| {
+            ui.label("Hello spectators!");
+        });
+    };
+
+    let mut driver = test_connect(&server, egui::vec2(800.0, 600.0));
+    server.accept_new_clients().unwrap();
+    let mut spectator = test_connect(&server, egui::vec2(400.0, 300.0));
+    spectator
+        .send_message(&ClientToServerMessage::Spectate {
+            client_id: Some(ClientId(0)),
+        })
+        .unwrap();
+
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut driver, &mut spectator],
+        &mut |i, message| match message {
+            ServerToClientMessage::SessionInfo(info) => {
+                i == 0 && info.spectators == vec![ClientId(1)] && info.spectating.is_none()
             }
+            ServerToClientMessage::Frame {
+                clipped_net_mesh,
+                textures_delta,
+                client_time,
+                ..
+            } => {
+                if i == 1 {
+                    // Scaled down to fit, with all the textures:
+                    let max_x = clipped_net_mesh
+                        .iter()
+                        .flat_map(|mesh| &mesh.mesh.pos)
+                        .map(|pos| pos.x)
+                        .fold(0.0, f32::max);
+                    assert!(max_x < 401.0, "{}", max_x); // plus some feathering
+                    assert!(client_time.is_none());
+                    !clipped_net_mesh.is_empty() && !textures_delta.set.is_empty()
+                } else {
+                    false
+                }
+            }
+            ServerToClientMessage::Custom(_) | ServerToClientMessage::Transfer(_) => false,
+        },
+    );
+
+    driver
+        .send_message(&ClientToServerMessage::HandOff { to: ClientId(1) })
+        .unwrap();
+
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut driver, &mut spectator],
+        &mut |i, message| match message {
+            ServerToClientMessage::SessionInfo(info) => {
+                if i == 0 {
+                    info.client_id == ClientId(1) && info.spectating == Some(ClientId(0))
+                } else {
+                    info.client_id == ClientId(0)
+                        && info.spectating.is_none()
+                        && info.spectators == vec![ClientId(1)]
+                }
+            }
+            _ => false,
+        },
+    );
+}
+
+#[test]
+fn test_spectating_is_refused_by_default() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    let mut ui = |_: &egui::Context, _: ClientId| {};
+
+    let _driver = test_connect(&server, egui::vec2(800.0, 600.0));
+    server.accept_new_clients().unwrap();
+    let mut spectator = test_connect(&server, egui::vec2(800.0, 600.0));
+    let mut is_session_info = |_: usize, message: ServerToClientMessage| match message {
+        ServerToClientMessage::SessionInfo(info) => {
+            assert_eq!(info.spectating, None);
+            true
         }
+        _ => false,
+    };
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut spectator],
+        &mut is_session_info,
+    );
+
+    // The refusal is sent as a reply:
+    spectator
+        .send_message(&ClientToServerMessage::Spectate {
+            client_id: Some(ClientId(0)),
+        })
+        .unwrap();
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut spectator],
+        &mut is_session_info,
+    );
+    assert!(server
+        .clients
+        .values()
+        .all(|client| client.spectating.is_none() && !client.session.has_texture_mirror()));
+}
+
+#[test]
+fn test_shared_session() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    server.set_shared_session(true);
+    let a_id = ClientId(1);
+
+    let mut a = test_connect(&server, egui::vec2(800.0, 600.0));
+    server.accept_new_clients().unwrap();
+    let mut b = test_connect(&server, egui::vec2(400.0, 300.0));
+
+    let move_pointer = |endpoint: &mut crate::TcpEndpoint, pos: egui::Pos2| {
+        let input = crate::messages::InputDelta {
+            events: vec![egui::Event::PointerMoved(pos)],
+            ..Default::default()
+        };
+        endpoint
+            .send_message(&ClientToServerMessage::Input {
+                input,
+                client_time: 0.0,
+            })
+            .unwrap();
+    };
+
+    let run = |server: &mut Server, a: &mut _, b: &mut _| {
+        let mut seen = vec![];
+        let mut frames = [vec![], vec![]];
+        test_run_until(
+            server,
+            &mut |egui_ctx, client_id| {
+                egui::CentralPanel::default().show(egui_ctx, |ui| {
+                    ui.label("Hello everyone!");
+                });
+                seen.push((client_id, egui_ctx.input().pointer.hover_pos()));
+            },
+            &mut [a, b],
+            &mut |i, message| match message {
+                ServerToClientMessage::Frame {
+                    clipped_net_mesh, ..
+                } => {
+                    let meshes: Vec<_> = clipped_net_mesh.into_iter().map(|m| m.mesh).collect();
+                    frames[i] = meshes;
+                    true
+                }
+                _ => false,
+            },
+        );
+        assert_eq!(frames[0], frames[1], "Everyone should get the same frame");
+        seen
+    };
+
+    move_pointer(&mut a, egui::pos2(10.0, 10.0));
+    let seen = run(&mut server, &mut a, &mut b);
+    assert_eq!(seen.last(), Some(&(a_id, Some(egui::pos2(10.0, 10.0)))));
+
+    // a has the pointer, so b must wait:
+    move_pointer(&mut b, egui::pos2(50.0, 50.0));
+    let seen = run(&mut server, &mut a, &mut b);
+    assert_eq!(seen.last(), Some(&(a_id, Some(egui::pos2(10.0, 10.0)))));
+}
+
+#[test]
+fn test_shared_session_audit() {
+    let records = Arc::new(Mutex::new(vec![]));
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    server.set_audit_log(Some(AuditLog::from_fn({
+        let records = records.clone();
+        move |record| records.lock().push(record.event.clone())
+    })));
+    server.set_shared_session(true);
+
+    let mut a = test_connect(&server, egui::vec2(800.0, 600.0));
+    server.accept_new_clients().unwrap();
+    let mut b = test_connect(&server, egui::vec2(800.0, 600.0));
+
+    let mut ui = |egui_ctx: &egui::Context, _: ClientId| {
+        egui::CentralPanel::default().show(egui_ctx, |ui| {
+            ui.add_sized(ui.available_size(), egui::Button::new("Restart"));
+        });
+    };
+    let is_frame = |_: usize, message: ServerToClientMessage| {
+        matches!(message, ServerToClientMessage::Frame { .. })
+    };
+    test_run_until(&mut server, &mut ui, &mut [&mut a, &mut b], &mut {
+        is_frame
+    });
+
+    let pos = egui::pos2(100.0, 100.0);
+    for pressed in [true, false] {
+        let input = crate::messages::InputDelta {
+            events: vec![
+                egui::Event::PointerMoved(pos),
+                egui::Event::PointerButton {
+                    pos,
+                    button: egui::PointerButton::Primary,
+                    pressed,
+                    modifiers: Default::default(),
+                },
+            ],
+            ..Default::default()
+        };
+        a.send_message(&ClientToServerMessage::Input {
+            input,
+            client_time: 0.0,
+        })
+        .unwrap();
+        test_run_until(&mut server, &mut ui, &mut [&mut a, &mut b], &mut {
+            is_frame
+        });
     }
+
+    assert!(records.lock().iter().any(|event| matches!(
+        event,
+        crate::audit::AuditEvent::Clicked { widget }
+            if widget.label.as_deref() == Some("Restart")
+    )));
+}
+
+#[test]
+fn test_presence() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    server.set_presence(true);
+
+    let mut a = test_connect(&server, egui::vec2(800.0, 600.0));
+    server.accept_new_clients().unwrap();
+    let mut b = test_connect(&server, egui::vec2(800.0, 600.0));
+
+    let pointer = egui::pos2(300.0, 200.0);
+    a.send_message(&ClientToServerMessage::SetName {
+        name: "Ada".to_owned(),
+    })
+    .unwrap();
+    a.send_message(&ClientToServerMessage::Input {
+        input: crate::messages::InputDelta {
+            events: vec![egui::Event::PointerMoved(pointer)],
+            ..Default::default()
+        },
+        client_time: 0.0,
+    })
+    .unwrap();
+
+    // Only b should see a cursor where a is pointing:
+    let mut near_pointer = [false; 2];
+    test_run_until(
+        &mut server,
+        &mut |egui_ctx, _| {
+            egui::CentralPanel::default().show(egui_ctx, |ui| {
+                ui.label("Who is here?");
+            });
+        },
+        &mut [&mut a, &mut b],
+        &mut |i, message| match message {
+            ServerToClientMessage::Frame {
+                clipped_net_mesh, ..
+            } => {
+                near_pointer[i] = clipped_net_mesh
+                    .iter()
+                    .flat_map(|mesh| &mesh.mesh.pos)
+                    .any(|pos| pos.distance(pointer) < 8.0);
+                true
+            }
+            _ => false,
+        },
+    );
+    assert_eq!(near_pointer, [false, true]);
+
+    let names: Vec<_> = remote_pointers(&server.clients)
+        .into_iter()
+        .map(|pointer| pointer.name)
+        .collect();
+    assert_eq!(names, vec![Some("Ada".to_owned())]);
+}
+
+// ----------------------------------------------------------------------------
+
+/// Estimates the offset between a client's clock and ours.
+///
+/// Network delays only ever make messages arrive late, so the smallest
+/// `server_time - client_time` seen recently is the best estimate of the offset
+/// (it includes the minimum one-way latency).
+/// Only recent samples are used, so that we follow clock drift.
+#[derive(Default)]
+struct ClockOffset {
+    /// (server time, server time - client time), oldest first.
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ClockOffset {
+    /// Samples older than this (in seconds) are forgotten.
+    const WINDOW: f64 = 30.0;
+
+    fn add(&mut self, server_time: f64, client_time: f64) {
+        while let Some(&(time, _)) = self.samples.front() {
+            if time < server_time - Self::WINDOW {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.samples
+            .push_back((server_time, server_time - client_time));
+    }
+
+    /// Add this to a client time to get our time.
+    fn offset(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .map(|&(_, offset)| offset)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+}
+
+#[test]
+fn test_clock_offset() {
+    let client_clock_start = 1_000_000.0;
+    let mut clock_offset = ClockOffset::default();
+
+    // Latency varies between 50 and 80 ms:
+    clock_offset.add(0.08, client_clock_start);
+    clock_offset.add(0.15, client_clock_start + 0.1);
+    clock_offset.add(0.27, client_clock_start + 0.2);
+    let offset = clock_offset.offset().unwrap();
+    assert!((offset - (0.05 - client_clock_start)).abs() < 1e-6);
+
+    // Old samples are forgotten:
+    clock_offset.add(100.5, client_clock_start + 100.0);
+    let offset = clock_offset.offset().unwrap();
+    assert!((offset - (0.5 - client_clock_start)).abs() < 1e-6);
+}
+
+#[test]
+fn test_upload() {
+    let upload = |server: &mut Server| {
+        let mut endpoint = test_connect(server, egui::vec2(800.0, 600.0));
+        let mut transfers = Transfers::new(Direction::Upload, 0);
+        let id = transfers.send("config.toml".to_owned(), b"answer = 42".to_vec());
+        while let Some(message) = transfers.next_message() {
+            endpoint
+                .send_message(&ClientToServerMessage::Transfer(message))
+                .unwrap();
+        }
+        (endpoint, id)
+    };
+
+    let dropped_files = std::cell::RefCell::new(vec![]);
+    let mut ui = |egui_ctx: &egui::Context, _: ClientId| {
+        let dropped = egui_ctx.input().raw.dropped_files.clone();
+        dropped_files.borrow_mut().extend(dropped);
+    };
+
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    let (mut operator, _) = upload(&mut server);
+    test_run_until(&mut server, &mut ui, &mut [&mut operator], &mut |_, _| {
+        !dropped_files.borrow().is_empty()
+    });
+    let file = dropped_files.borrow_mut().remove(0);
+    assert_eq!(file.name, "config.toml");
+    assert_eq!(file.bytes.as_deref(), Some(&b"answer = 42"[..]));
+
+    // Viewers may not upload:
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    server.set_role_fn(|_| Role::Viewer);
+    let (mut viewer, id) = upload(&mut server);
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut viewer],
+        &mut |_, message| {
+            matches!(
+                message,
+                ServerToClientMessage::Transfer(TransferMessage::Cancel { id: cancelled, .. }) if cancelled == id
+            )
+        },
+    );
+    assert!(dropped_files.borrow().is_empty());
+}
+
+#[test]
+fn test_custom_messages_from_viewers_are_dropped() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    server.set_role_fn(|_| Role::Viewer);
+    server.on_custom("greeting", {
+        let received = received.clone();
+        move |_, greeting: String| received.lock().push(greeting)
+    });
+    let mut ui = |_: &egui::Context, _: ClientId| {};
+
+    let mut client = test_connect(&server, egui::vec2(800.0, 600.0));
+    let send_greeting = |client: &mut crate::TcpEndpoint, greeting: &str| {
+        let message = CustomMessage::new("greeting", greeting).unwrap();
+        client
+            .send_message(&ClientToServerMessage::Custom(message))
+            .unwrap();
+    };
+    send_greeting(&mut client, "from a viewer");
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut client],
+        &mut |_, message| matches!(message, ServerToClientMessage::Frame { .. }),
+    );
+    assert!(received.lock().is_empty());
+
+    server.set_client_role(ClientId(0), Role::Operator);
+    send_greeting(&mut client, "from an operator");
+    test_run_until(&mut server, &mut ui, &mut [&mut client], &mut |_, _| {
+        !received.lock().is_empty()
+    });
+    assert_eq!(*received.lock(), ["from an operator"]);
+}
+
+#[test]
+fn test_ime_round_trip() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    let text = std::cell::RefCell::new(String::new());
+    let mut ui = |egui_ctx: &egui::Context, _: ClientId| {
+        egui::CentralPanel::default().show(egui_ctx, |ui| {
+            ui.text_edit_singleline(&mut *text.borrow_mut())
+                .request_focus();
+        });
+    };
+    let text_cursor_pos = |message: &ServerToClientMessage| match message {
+        ServerToClientMessage::Frame {
+            platform_output, ..
+        } => platform_output.text_cursor_pos,
+        _ => None,
+    };
+    let compose = |client: &mut crate::TcpEndpoint, events: Vec<egui::Event>| {
+        client
+            .send_message(&ClientToServerMessage::Input {
+                input: crate::messages::InputDelta {
+                    events,
+                    ..Default::default()
+                },
+                client_time: 0.0,
+            })
+            .unwrap();
+    };
+
+    let mut client = test_connect(&server, egui::vec2(800.0, 600.0));
+
+    // The server tells the client where to put the IME candidate window:
+    let mut start_pos = None;
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut client],
+        &mut |_, message| {
+            start_pos = text_cursor_pos(&message);
+            start_pos.is_some()
+        },
+    );
+
+    // Preedit text is shown in the text field, and the cursor follows it:
+    compose(
+        &mut client,
+        vec![
+            egui::Event::CompositionStart,
+            egui::Event::CompositionUpdate("にほ".to_owned()),
+        ],
+    );
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut client],
+        &mut |_, message| {
+            let moved = matches!(
+                (text_cursor_pos(&message), start_pos),
+                (Some(pos), Some(start)) if pos.x > start.x
+            );
+            moved && *text.borrow() == "にほ"
+        },
+    );
+
+    // Committing replaces the preedit text:
+    compose(
+        &mut client,
+        vec![egui::Event::CompositionEnd("日本".to_owned())],
+    );
+    test_run_until(
+        &mut server,
+        &mut ui,
+        &mut [&mut client],
+        &mut |_, message| text_cursor_pos(&message).is_some() && *text.borrow() == "日本",
+    );
+}
+
+#[test]
+fn test_capabilities() {
+    use crate::capabilities::Theme;
+
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    let seen = std::cell::RefCell::new(Capabilities::default());
+    let max_texture_side = std::cell::Cell::new(0);
+    let mut ui = |egui_ctx: &egui::Context, _: ClientId| {
+        *seen.borrow_mut() = Capabilities::of(egui_ctx);
+        max_texture_side.set(egui_ctx.input().max_texture_side);
+    };
+
+    let capabilities = Capabilities {
+        theme: Some(Theme::Dark),
+        pixels_per_point: Some(2.0),
+        max_texture_side: Some(4096),
+        ..Capabilities::new("test_viewer", "1.2.3")
+    };
+    let mut client = test_connect(&server, egui::vec2(800.0, 600.0));
+    client
+        .send_message(&ClientToServerMessage::Hello(capabilities.clone()))
+        .unwrap();
+
+    test_run_until(&mut server, &mut ui, &mut [&mut client], &mut |_, _| {
+        *seen.borrow() == capabilities
+    });
+    assert_eq!(server.capabilities(ClientId(0)), Some(capabilities));
+    assert_eq!(max_texture_side.get(), 4096);
+}
+
+#[test]
+fn test_reconnect_resets_input_state() {
+    let mut session = Session::new(ClientId(0));
+    let input = |input: crate::messages::InputDelta| ClientToServerMessage::Input {
+        input,
+        client_time: 0.0,
+    };
+
+    session.on_message(input(crate::messages::InputDelta {
+        modifiers: Some(egui::Modifiers::SHIFT),
+        screen_rect: Some(egui::Rect::from_min_size(
+            egui::Pos2::ZERO,
+            egui::vec2(800.0, 600.0),
+        )),
+        ..Default::default()
+    }));
+    session.reset_input();
+
+    // The new connection starts its deltas from `RawInput::default()`,
+    // so it doesn't mention that shift is no longer held:
+    session.on_message(input(Default::default()));
+    let raw_input = session.new_input.pop().unwrap();
+    assert!(session.new_input.is_empty());
+    assert_eq!(raw_input.modifiers, egui::Modifiers::default());
+    assert_eq!(raw_input.screen_rect, None);
+    assert_eq!(session.screen_rect(), None);
+}
+
+#[test]
+fn test_queued_input_is_limited() {
+    let mut session = Session::new(ClientId(0));
+    session.input_mode = InputMode::Lossless {
+        keep_intermediate_output: false,
+    };
+    session.input_limits.max_events = 10;
+    let input = |num_events: usize| ClientToServerMessage::Input {
+        input: crate::messages::InputDelta {
+            events: vec![egui::Event::PointerGone; num_events],
+            ..Default::default()
+        },
+        client_time: 0.0,
+    };
+    let queued = |session: &Session| -> usize {
+        session
+            .new_input
+            .iter()
+            .map(|input| input.events.len().max(1))
+            .sum()
+    };
+
+    for _ in 0..100 {
+        session.on_message(input(3));
+    }
+    session.on_message(input(0));
+    assert_eq!(queued(&session), 10);
+
+    // The state still gets through:
+    session.on_message(ClientToServerMessage::Input {
+        input: crate::messages::InputDelta {
+            modifiers: Some(egui::Modifiers::SHIFT),
+            ..Default::default()
+        },
+        client_time: 0.0,
+    });
+    assert_eq!(queued(&session), 10);
+    assert_eq!(
+        session.new_input.last().unwrap().modifiers,
+        egui::Modifiers::SHIFT
+    );
+
+    session.input_limits.policy = ViolationPolicy::Disconnect;
+    assert!(matches!(
+        session.on_message(input(1)),
+        ControlFlow::Disconnect
+    ));
+}
+
+#[test]
+fn test_access_list_change_disconnects() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    let _client = test_connect(&server, egui::vec2(800.0, 600.0));
+    let is_connected = |server: &Server| {
+        server
+            .clients
+            .values()
+            .any(|client| client.tcp_endpoint.is_some())
+    };
+
+    let start = Instant::now();
+    while !is_connected(&server) {
+        server.show(|_, _| {}).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5), "Never connected");
+    }
+
+    server
+        .access_list()
+        .set(crate::AccessList::parse(&[], &["127.0.0.0/8"]).unwrap());
+    server.show(|_, _| {}).unwrap();
+    assert!(!is_connected(&server));
+    assert!(server.clients.is_empty());
+}
+
+#[test]
+fn test_disconnected_clients_are_removed() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    let mut endpoint = test_connect(&server, egui::vec2(800.0, 600.0));
+
+    let start = Instant::now();
+    while server.clients.is_empty() {
+        server.show(|_, _| {}).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5), "Never connected");
+    }
+
+    endpoint
+        .send_message(&ClientToServerMessage::Goodbye)
+        .unwrap();
+    while !server.clients.is_empty() {
+        server.show(|_, _| {}).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5), "Never removed");
+    }
+}
+
+#[test]
+fn test_features_are_gated() {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    let mut endpoint = test_connect(&server, egui::vec2(800.0, 600.0));
+    let start = Instant::now();
+    while server.clients.is_empty() {
+        server.show(|_, _| {}).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5), "Never connected");
+    }
+
+    // No hello, no features:
+    let id = ClientId(0);
+    assert!(server.send_custom(id, "numbers", &1_i32).is_err());
+    assert!(server.send_file(id, "report.txt", vec![]).is_err());
+
+    endpoint
+        .send_message(&ClientToServerMessage::Hello(Capabilities::new(
+            "test", "0",
+        )))
+        .unwrap();
+    while server.capabilities(id).map(|c| c.has_feature("custom")) != Some(true) {
+        server.show(|_, _| {}).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5), "No hello");
+    }
+    assert!(server.send_custom(id, "numbers", &1_i32).is_ok());
+    assert!(server.send_file(id, "report.txt", vec![]).is_ok());
+
+    let mut session = Session::new(id);
+    let frame = || EtermFrame {
+        frame_index: 0,
+        platform_output: egui::PlatformOutput {
+            copied_text: "secret".to_owned(),
+            open_url: Some(egui::output::OpenUrl::same_tab("https://example.com")),
+            ..Default::default()
+        },
+        clipped_net_mesh: vec![],
+        textures_delta: Default::default(),
+    };
+    let platform_output = |message| match message {
+        ServerToClientMessage::Frame {
+            platform_output, ..
+        } => platform_output,
+        _ => panic!("Expected a frame"),
+    };
+    let output = platform_output(session.frame_message(frame()));
+    assert!(output.copied_text.is_empty());
+    assert!(output.open_url.is_none());
+
+    session.capabilities = Capabilities::new("test", "0");
+    let output = platform_output(session.frame_message(frame()));
+    assert_eq!(output.copied_text, "secret");
+    assert!(output.open_url.is_some());
 }
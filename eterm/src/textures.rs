@@ -0,0 +1,129 @@
+use egui::{epaint::ImageDelta, ImageData, TextureId, TexturesDelta};
+use std::collections::HashMap;
+
+/// A copy of all the textures a client has been sent so far.
+///
+/// Lets us bring a client that joins late (e.g. a spectator) up to date
+/// with one full [`TexturesDelta`].
+#[derive(Default)]
+pub(crate) struct TextureMirror {
+    /// Always whole images (`pos == None`).
+    textures: HashMap<TextureId, ImageDelta>,
+}
+
+impl TextureMirror {
+    pub(crate) fn apply(&mut self, delta: &TexturesDelta) {
+        for (id, image_delta) in &delta.set {
+            match image_delta.pos {
+                None => {
+                    self.textures.insert(*id, image_delta.clone());
+                }
+                Some(pos) => {
+                    if let Some(texture) = self.textures.get_mut(id) {
+                        texture.filter = image_delta.filter;
+                        blit(&mut texture.image, pos, &image_delta.image);
+                    } else {
+                        tracing::warn!("Partial update of unknown texture {:?}", id);
+                    }
+                }
+            }
+        }
+        for id in &delta.free {
+            self.textures.remove(id);
+        }
+    }
+
+    /// Everything needed to recreate all current textures from scratch.
+    pub(crate) fn full_delta(&self) -> TexturesDelta {
+        TexturesDelta {
+            set: self
+                .textures
+                .iter()
+                .map(|(id, image_delta)| (*id, image_delta.clone()))
+                .collect(),
+            free: vec![],
+        }
+    }
+}
+
+/// Copy `src` into `dst` at `pos`, clipping to the size of `dst`.
+fn blit(dst: &mut ImageData, [x, y]: [usize; 2], src: &ImageData) {
+    fn blit_pixels<T: Copy>(
+        dst: &mut [T],
+        dst_width: usize,
+        dst_height: usize,
+        [x, y]: [usize; 2],
+        src: &[T],
+        [src_width, src_height]: [usize; 2],
+    ) {
+        let w = src_width.min(dst_width.saturating_sub(x));
+        for row in 0..src_height.min(dst_height.saturating_sub(y)) {
+            let dst_start = (y + row) * dst_width + x;
+            let src_start = row * src_width;
+            dst[dst_start..dst_start + w].copy_from_slice(&src[src_start..src_start + w]);
+        }
+    }
+
+    match (dst, src) {
+        (ImageData::Color(dst), ImageData::Color(src)) => {
+            let [w, h] = dst.size;
+            blit_pixels(&mut dst.pixels, w, h, [x, y], &src.pixels, src.size);
+        }
+        (ImageData::Font(dst), ImageData::Font(src)) => {
+            let [w, h] = dst.size;
+            blit_pixels(&mut dst.pixels, w, h, [x, y], &src.pixels, src.size);
+        }
+        _ => {
+            tracing::warn!("Partial texture update with a different image type");
+        }
+    }
+}
+
+#[test]
+fn test_texture_mirror() {
+    use egui::{Color32, ColorImage, TextureFilter};
+
+    let id = TextureId::Managed(1);
+    let mut mirror = TextureMirror::default();
+
+    mirror.apply(&TexturesDelta {
+        set: vec![(
+            id,
+            ImageDelta::full(
+                ColorImage::new([4, 4], Color32::BLACK),
+                TextureFilter::Linear,
+            ),
+        )],
+        free: vec![],
+    });
+    mirror.apply(&TexturesDelta {
+        set: vec![(
+            id,
+            ImageDelta::partial(
+                [3, 2],
+                ColorImage::new([2, 1], Color32::WHITE),
+                TextureFilter::Linear,
+            ),
+        )],
+        free: vec![],
+    });
+
+    let full = mirror.full_delta();
+    assert_eq!(full.set.len(), 1);
+    assert!(full.set[0].1.is_whole());
+    match &full.set[0].1.image {
+        ImageData::Color(image) => {
+            assert_eq!(image.size, [4, 4]);
+            assert_eq!(image.pixels[2 * 4 + 3], Color32::WHITE);
+            assert_eq!(image.pixels[2 * 4 + 2], Color32::BLACK);
+            assert_eq!(image.pixels[3 * 4 + 3], Color32::BLACK);
+        }
+        ImageData::Font(_) => panic!("Expected a color image"),
+    }
+
+    mirror.apply(&TexturesDelta {
+        set: vec![],
+        free: vec![id],
+    });
+    assert!(mirror.full_delta().set.is_empty());
+}
@@ -0,0 +1,665 @@
+//! File transfers over the eterm connection.
+//!
+//! * Uploads: files dropped onto the viewer are sent to the server,
+//!   and show up in the ui as [`egui::RawInput::dropped_files`] once complete.
+//! * Downloads: the server sends a file with [`crate::Server::send_file`],
+//!   and the client picks it up with [`crate::Client::take_downloads`].
+//!
+//! Files are sent in chunks of [`CHUNK_SIZE`], a few per tick, interleaved with the frames
+//! so that a large file does not stall the ui. Uploads are paced at [`UPLOAD_BYTES_PER_SECOND`]
+//! to stay below the rate limit of the server. Either side can cancel a transfer,
+//! and the receiving side refuses files larger than its limit,
+//! and more than [`MAX_INCOMING_TRANSFERS`] at a time.
+//!
+//! The progress of uploads can be shown in the ui:
+//!
+//! ```
+//! # let egui_ctx = egui::Context::default();
+//! # let _ = egui_ctx.run(Default::default(), |egui_ctx| {
+//! egui::CentralPanel::default().show(egui_ctx, |ui| {
+//!     for transfer in eterm::transfer::progress(egui_ctx) {
+//!         ui.add(egui::ProgressBar::new(transfer.fraction()).text(&transfer.name));
+//!     }
+//! });
+//! # });
+//! ```
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Instant,
+};
+
+/// Files are sent in pieces of this many bytes.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks each side sends per tick (per client, on the server).
+pub(crate) const CHUNKS_PER_TICK: usize = 4;
+
+/// How fast a [`crate::Client`] uploads: half of what a server allows by default
+/// (see [`crate::RateLimit::transfer_bytes_per_second`]).
+pub const UPLOAD_BYTES_PER_SECOND: f32 = 2_000_000.0;
+
+/// How many files may be coming in at the same time, per connection.
+///
+/// Together they may not be larger than the limit on the size of a single file.
+pub const MAX_INCOMING_TRANSFERS: usize = 4;
+
+/// Largest file a [`crate::Client`] uploads.
+///
+/// The server usually allows much less, see [`crate::InputLimits::max_dropped_file_size`].
+pub const MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Default limit on the size of a file a [`crate::Client`] downloads.
+///
+/// The server limits uploads with [`crate::InputLimits::max_dropped_file_size`].
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub enum Direction {
+    /// From the client to the server.
+    Upload,
+
+    /// From the server to the client.
+    Download,
+}
+
+/// Identifies a transfer on one connection.
+///
+/// Assigned by the sending side, with separate numbering per [`Direction`].
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub struct TransferId {
+    pub direction: Direction,
+    pub index: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TransferMessage {
+    /// From the sender: a new file is coming.
+    Start {
+        id: TransferId,
+        name: String,
+        size: u64,
+    },
+
+    /// From the sender: the next piece of the file.
+    Chunk { id: TransferId, bytes: Vec<u8> },
+
+    /// From either side: stop the transfer.
+    Cancel { id: TransferId, reason: String },
+}
+
+impl TransferMessage {
+    /// The size of the file contents in this message.
+    pub(crate) fn payload_len(&self) -> usize {
+        match self {
+            Self::Chunk { bytes, .. } => bytes.len(),
+            Self::Start { .. } | Self::Cancel { .. } => 0,
+        }
+    }
+}
+
+/// How far along a transfer is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub id: TransferId,
+    pub name: String,
+    /// Total size in bytes.
+    pub size: u64,
+    /// Bytes sent or received so far.
+    pub transferred: u64,
+}
+
+impl TransferProgress {
+    /// In the range `0..=1`.
+    pub fn fraction(&self) -> f32 {
+        if self.size == 0 {
+            1.0
+        } else {
+            self.transferred as f32 / self.size as f32
+        }
+    }
+}
+
+/// The uploads from (and downloads to) the client whose ui is currently being shown.
+///
+/// Call this from within the `do_ui` closure.
+pub fn progress(egui_ctx: &egui::Context) -> Vec<TransferProgress> {
+    egui_ctx.data().get_temp(progress_id()).unwrap_or_default()
+}
+
+pub(crate) fn set_progress(egui_ctx: &egui::Context, progress: Vec<TransferProgress>) {
+    egui_ctx.data().insert_temp(progress_id(), progress);
+}
+
+fn progress_id() -> egui::Id {
+    egui::Id::new("eterm::transfer::progress")
+}
+
+/// A file that has been fully received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedFile {
+    /// As given by the sender. Don't trust it, see [`Self::safe_file_name`].
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+impl ReceivedFile {
+    /// The name without any directories or special characters,
+    /// so it can be used as a file name without escaping the directory it is saved in.
+    pub fn safe_file_name(&self) -> String {
+        let name = self.name.rsplit(['/', '\\']).next().unwrap_or_default();
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_control() || "<>:\"|?*".contains(c) {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect();
+        let name = name.trim().trim_start_matches('.');
+        if name.is_empty() {
+            "download".to_owned()
+        } else {
+            name.to_owned()
+        }
+    }
+
+    pub(crate) fn into_dropped_file(self) -> egui::DroppedFile {
+        egui::DroppedFile {
+            name: self.name,
+            bytes: Some(self.bytes.into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Where the contents of an outgoing file come from.
+enum Source {
+    Bytes(Vec<u8>),
+
+    /// Read one chunk at a time, by whoever sends them.
+    File(std::fs::File),
+}
+
+struct OutgoingTransfer {
+    id: TransferId,
+    name: String,
+    size: u64,
+    source: Source,
+    started: bool,
+    sent: u64,
+}
+
+impl OutgoingTransfer {
+    fn read_chunk(&mut self) -> std::io::Result<Vec<u8>> {
+        let len = (self.size - self.sent).min(CHUNK_SIZE as u64) as usize;
+        let bytes = match &mut self.source {
+            Source::Bytes(bytes) => {
+                let start = self.sent as usize;
+                bytes[start..start + len].to_vec()
+            }
+            Source::File(file) => {
+                use std::io::Read as _;
+                let mut bytes = vec![0; len];
+                file.read_exact(&mut bytes)?;
+                bytes
+            }
+        };
+        self.sent += len as u64;
+        Ok(bytes)
+    }
+}
+
+struct IncomingTransfer {
+    name: String,
+    size: u64,
+    bytes: Vec<u8>,
+}
+
+/// The transfers of one connection, on one side of it.
+pub(crate) struct Transfers {
+    /// Which way the files we send go.
+    outgoing_direction: Direction,
+    next_index: u64,
+    max_incoming_size: u64,
+    /// If not, all incoming files are refused.
+    incoming_enabled: bool,
+    /// Sent before any chunks.
+    replies: VecDeque<TransferMessage>,
+    /// Taking turns, one chunk at a time.
+    outgoing: VecDeque<OutgoingTransfer>,
+    incoming: BTreeMap<TransferId, IncomingTransfer>,
+    received: Vec<ReceivedFile>,
+}
+
+impl Transfers {
+    pub(crate) fn new(outgoing_direction: Direction, max_incoming_size: u64) -> Self {
+        Self {
+            outgoing_direction,
+            next_index: 0,
+            max_incoming_size,
+            incoming_enabled: true,
+            replies: Default::default(),
+            outgoing: Default::default(),
+            incoming: Default::default(),
+            received: Default::default(),
+        }
+    }
+
+    pub(crate) fn set_max_incoming_size(&mut self, max_incoming_size: u64) {
+        self.max_incoming_size = max_incoming_size;
+    }
+
+    pub(crate) fn set_incoming_enabled(&mut self, incoming_enabled: bool) {
+        self.incoming_enabled = incoming_enabled;
+    }
+
+    /// Queue a file for sending.
+    pub(crate) fn send(&mut self, name: String, bytes: Vec<u8>) -> TransferId {
+        let size = bytes.len() as u64;
+        self.send_from(name, size, Source::Bytes(bytes))
+    }
+
+    /// Queue a file for sending, reading it as we go.
+    pub(crate) fn send_file(&mut self, name: String, file: std::fs::File, size: u64) -> TransferId {
+        self.send_from(name, size, Source::File(file))
+    }
+
+    fn send_from(&mut self, name: String, size: u64, source: Source) -> TransferId {
+        let id = TransferId {
+            direction: self.outgoing_direction,
+            index: self.next_index,
+        };
+        self.next_index += 1;
+        tracing::debug!("Sending {:?} ({} bytes) as {:?}", name, size, id);
+        self.outgoing.push_back(OutgoingTransfer {
+            id,
+            name,
+            size,
+            source,
+            started: false,
+            sent: 0,
+        });
+        id
+    }
+
+    /// Stop a transfer in either direction, and tell the other side.
+    ///
+    /// Returns `false` if there is no such transfer (e.g. because it is already done).
+    pub(crate) fn cancel(&mut self, id: TransferId, reason: &str) -> bool {
+        if let Some(index) = self.outgoing.iter().position(|transfer| transfer.id == id) {
+            let started = matches!(self.outgoing.remove(index), Some(transfer) if transfer.started);
+            if started {
+                self.reply(TransferMessage::Cancel {
+                    id,
+                    reason: reason.to_owned(),
+                });
+            }
+            true
+        } else if self.incoming.remove(&id).is_some() {
+            self.reply(TransferMessage::Cancel {
+                id,
+                reason: reason.to_owned(),
+            });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Refuse a transfer the other side is starting.
+    pub(crate) fn refuse(&mut self, id: TransferId, reason: String) {
+        tracing::warn!("Refusing transfer {:?}: {}", id, reason);
+        self.incoming.remove(&id);
+        self.reply(TransferMessage::Cancel { id, reason });
+    }
+
+    fn reply(&mut self, message: TransferMessage) {
+        self.replies.push_back(message);
+    }
+
+    pub(crate) fn on_message(&mut self, message: TransferMessage) {
+        match message {
+            TransferMessage::Start { id, name, size } => {
+                let in_flight: u64 = self.incoming.values().map(|transfer| transfer.size).sum();
+                if id.direction == self.outgoing_direction {
+                    tracing::warn!("The other side started a transfer in our direction");
+                } else if !self.incoming_enabled {
+                    self.refuse(
+                        id,
+                        format!("{:?} was refused: transfers are disabled", name),
+                    );
+                } else if size > self.max_incoming_size {
+                    self.refuse(
+                        id,
+                        format!(
+                            "{:?} is too large: {:.1} MB (the limit is {:.1} MB)",
+                            name,
+                            size as f64 * 1e-6,
+                            self.max_incoming_size as f64 * 1e-6
+                        ),
+                    );
+                } else if self.incoming.len() >= MAX_INCOMING_TRANSFERS {
+                    self.refuse(
+                        id,
+                        format!(
+                            "{:?} was refused: at most {} files at a time",
+                            name, MAX_INCOMING_TRANSFERS
+                        ),
+                    );
+                } else if in_flight + size > self.max_incoming_size {
+                    self.refuse(
+                        id,
+                        format!(
+                            "{:?} was refused: too much in progress ({:.1} MB, the limit is {:.1} MB)",
+                            name,
+                            (in_flight + size) as f64 * 1e-6,
+                            self.max_incoming_size as f64 * 1e-6
+                        ),
+                    );
+                } else if size == 0 {
+                    self.received.push(ReceivedFile {
+                        name,
+                        bytes: vec![],
+                    });
+                } else {
+                    self.incoming.insert(
+                        id,
+                        IncomingTransfer {
+                            name,
+                            size,
+                            bytes: vec![],
+                        },
+                    );
+                }
+            }
+
+            TransferMessage::Chunk { id, bytes } => {
+                let transfer = match self.incoming.get_mut(&id) {
+                    Some(transfer) => transfer,
+                    None => return, // e.g. cancelled or refused
+                };
+                if (transfer.bytes.len() + bytes.len()) as u64 > transfer.size {
+                    self.refuse(id, "Received more bytes than announced".to_owned());
+                    return;
+                }
+                transfer.bytes.extend_from_slice(&bytes);
+                if transfer.bytes.len() as u64 == transfer.size {
+                    if let Some(transfer) = self.incoming.remove(&id) {
+                        tracing::debug!("Received {:?} ({} bytes)", transfer.name, transfer.size);
+                        self.received.push(ReceivedFile {
+                            name: transfer.name,
+                            bytes: transfer.bytes,
+                        });
+                    }
+                }
+            }
+
+            TransferMessage::Cancel { id, reason } => {
+                let known = if id.direction == self.outgoing_direction {
+                    let num_outgoing = self.outgoing.len();
+                    self.outgoing.retain(|transfer| transfer.id != id);
+                    self.outgoing.len() != num_outgoing
+                } else {
+                    self.incoming.remove(&id).is_some()
+                };
+                if known {
+                    tracing::info!("Transfer {:?} was cancelled: {}", id, reason);
+                }
+            }
+        }
+    }
+
+    /// The next message to send, if any.
+    pub(crate) fn next_message(&mut self) -> Option<TransferMessage> {
+        if let Some(reply) = self.replies.pop_front() {
+            return Some(reply);
+        }
+
+        let mut transfer = self.outgoing.pop_front()?;
+        let message = if transfer.started {
+            match transfer.read_chunk() {
+                Ok(bytes) => TransferMessage::Chunk {
+                    id: transfer.id,
+                    bytes,
+                },
+                Err(err) => {
+                    tracing::error!("Failed to read {:?}: {}", transfer.name, err);
+                    return Some(TransferMessage::Cancel {
+                        id: transfer.id,
+                        reason: format!("Failed to read {:?}", transfer.name),
+                    });
+                }
+            }
+        } else {
+            transfer.started = true;
+            TransferMessage::Start {
+                id: transfer.id,
+                name: transfer.name.clone(),
+                size: transfer.size,
+            }
+        };
+
+        if transfer.sent < transfer.size {
+            self.outgoing.push_back(transfer); // let the others have a turn
+        }
+        Some(message)
+    }
+
+    /// All transfers in progress, both ways.
+    pub(crate) fn progress(&self) -> Vec<TransferProgress> {
+        let outgoing = self.outgoing.iter().map(|transfer| TransferProgress {
+            id: transfer.id,
+            name: transfer.name.clone(),
+            size: transfer.size,
+            transferred: transfer.sent,
+        });
+        let incoming = self.incoming.iter().map(|(id, transfer)| TransferProgress {
+            id: *id,
+            name: transfer.name.clone(),
+            size: transfer.size,
+            transferred: transfer.bytes.len() as u64,
+        });
+        outgoing.chain(incoming).collect()
+    }
+
+    pub(crate) fn has_received(&self) -> bool {
+        !self.received.is_empty()
+    }
+
+    pub(crate) fn take_received(&mut self) -> Vec<ReceivedFile> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// The connection was lost: drop everything in progress.
+    pub(crate) fn clear(&mut self) {
+        for transfer in self.progress() {
+            tracing::warn!("Transfer of {:?} was interrupted", transfer.name);
+        }
+        self.replies.clear();
+        self.outgoing.clear();
+        self.incoming.clear();
+    }
+}
+
+/// Spreads what we send over time, so that we stay below a rate limit.
+pub(crate) struct Pacer {
+    bytes_per_second: f32,
+    /// Bytes we may send right now. Goes into debt for a large message.
+    budget: f32,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(bytes_per_second: f32, now: Instant) -> Self {
+        Self {
+            bytes_per_second,
+            budget: 0.0,
+            last_refill: now,
+        }
+    }
+
+    /// May we send something now?
+    pub(crate) fn ready(&mut self, now: Instant) -> bool {
+        let dt = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        let max_budget = (CHUNKS_PER_TICK * CHUNK_SIZE) as f32;
+        self.budget = (self.budget + self.bytes_per_second * dt.as_secs_f32()).min(max_budget);
+        self.budget > 0.0
+    }
+
+    pub(crate) fn consume(&mut self, num_bytes: usize) {
+        self.budget -= num_bytes as f32;
+    }
+
+    /// How long until [`Self::ready`] returns `true` (roughly).
+    pub(crate) fn time_until_ready(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f32((-self.budget / self.bytes_per_second).max(0.0) + 1e-3)
+    }
+}
+
+#[test]
+fn test_transfers() {
+    fn pump(from: &mut Transfers, to: &mut Transfers) {
+        while let Some(message) = from.next_message() {
+            to.on_message(message);
+        }
+    }
+
+    let mut client = Transfers::new(Direction::Upload, 1_000);
+    let mut server = Transfers::new(Direction::Download, 200_000);
+
+    let large: Vec<u8> = (0..150_000_u32).map(|i| i as u8).collect();
+    let large_id = client.send("large.bin".to_owned(), large.clone());
+    client.send("empty.txt".to_owned(), vec![]);
+
+    // Start both, then the first chunk of the large one:
+    for _ in 0..3 {
+        server.on_message(client.next_message().unwrap());
+    }
+    assert_eq!(
+        server.progress(),
+        vec![TransferProgress {
+            id: large_id,
+            name: "large.bin".to_owned(),
+            size: 150_000,
+            transferred: CHUNK_SIZE as u64,
+        }]
+    );
+    assert_eq!(server.take_received().len(), 1); // the empty one
+
+    pump(&mut client, &mut server);
+    assert!(client.progress().is_empty());
+    assert_eq!(
+        server.take_received(),
+        vec![ReceivedFile {
+            name: "large.bin".to_owned(),
+            bytes: large.clone(),
+        }]
+    );
+
+    // Too large for the client:
+    let refused = server.send("heap.prof".to_owned(), large);
+    client.on_message(server.next_message().unwrap());
+    assert!(client.progress().is_empty());
+    assert_eq!(server.progress().len(), 1);
+    pump(&mut client, &mut server); // the refusal
+    assert!(server.progress().is_empty());
+    assert!(!client.has_received());
+
+    // Cancelled by the receiver:
+    client.set_max_incoming_size(1_000_000);
+    let cancelled = server.send("heap.prof".to_owned(), vec![0; 3 * CHUNK_SIZE]);
+    assert_ne!(cancelled, refused);
+    client.on_message(server.next_message().unwrap());
+    client.on_message(server.next_message().unwrap());
+    assert!(client.cancel(cancelled, "No thanks"));
+    pump(&mut client, &mut server);
+    assert!(server.progress().is_empty());
+    pump(&mut server, &mut client);
+    assert!(!client.has_received());
+
+    // Too many at once:
+    let mut server = Transfers::new(Direction::Download, 10 * CHUNK_SIZE as u64);
+    for i in 0..=MAX_INCOMING_TRANSFERS {
+        let id = client.send(format!("{}.bin", i), vec![0; 2 * CHUNK_SIZE]);
+        client.next_message(); // start
+        server.on_message(TransferMessage::Start {
+            id,
+            name: format!("{}.bin", i),
+            size: 2 * CHUNK_SIZE as u64,
+        });
+    }
+    assert_eq!(server.progress().len(), MAX_INCOMING_TRANSFERS);
+
+    // Too much at once:
+    let mut server = Transfers::new(Direction::Download, 10 * CHUNK_SIZE as u64);
+    for i in 0..3 {
+        let id = client.send(format!("{}.bin", i), vec![0; 4 * CHUNK_SIZE]);
+        server.on_message(TransferMessage::Start {
+            id,
+            name: format!("{}.bin", i),
+            size: 4 * CHUNK_SIZE as u64,
+        });
+    }
+    assert_eq!(server.progress().len(), 2);
+
+    // Nothing at all:
+    let mut server = Transfers::new(Direction::Download, 10 * CHUNK_SIZE as u64);
+    server.set_incoming_enabled(false);
+    let id = client.send("x.bin".to_owned(), vec![0; 10]);
+    server.on_message(TransferMessage::Start {
+        id,
+        name: "x.bin".to_owned(),
+        size: 10,
+    });
+    assert!(server.progress().is_empty());
+    assert!(matches!(
+        server.next_message(),
+        Some(TransferMessage::Cancel { id: refused, .. }) if refused == id
+    ));
+}
+
+#[test]
+fn test_send_file() {
+    let path = std::env::temp_dir().join(format!("eterm_test_send_file_{}", std::process::id()));
+    let contents: Vec<u8> = (0..(CHUNK_SIZE + 100) as u32).map(|i| i as u8).collect();
+    std::fs::write(&path, &contents).unwrap();
+
+    let mut client = Transfers::new(Direction::Upload, 0);
+    let mut server = Transfers::new(Direction::Download, 1_000_000);
+    let file = std::fs::File::open(&path).unwrap();
+    client.send_file("data.bin".to_owned(), file, contents.len() as u64);
+    std::fs::remove_file(&path).ok();
+
+    while let Some(message) = client.next_message() {
+        server.on_message(message);
+    }
+    assert_eq!(
+        server.take_received(),
+        vec![ReceivedFile {
+            name: "data.bin".to_owned(),
+            bytes: contents,
+        }]
+    );
+}
+
+#[test]
+fn test_safe_file_name() {
+    let file = |name: &str| ReceivedFile {
+        name: name.to_owned(),
+        bytes: vec![],
+    };
+    assert_eq!(file("heap.prof").safe_file_name(), "heap.prof");
+    assert_eq!(file("../../etc/passwd").safe_file_name(), "passwd");
+    assert_eq!(file("C:\\evil\\x.exe").safe_file_name(), "x.exe");
+    assert_eq!(file("..").safe_file_name(), "download");
+    assert_eq!(file(".bashrc").safe_file_name(), "bashrc");
+    assert_eq!(file("a\nb?.txt").safe_file_name(), "a_b_.txt");
+}
@@ -1,23 +1,97 @@
 use egui::{epaint::Primitive, ClippedPrimitive, Mesh};
 use eterm::{messages::ClippedNetMesh, EtermFrame};
 use glium::glutin::{self, event_loop::EventLoopBuilder};
+use std::path::{Path, PathBuf};
+
+mod url_prompt;
+
+use url_prompt::UrlPrompt;
 
 /// Color to clear the canvas before painting a frame
 const CLEAR_COLOR: egui::Rgba = egui::Rgba::from_rgb(0.5, 0.3, 0.2);
 
+const TITLE: &str = "eterm viewer";
+
+/// Most files we save from one server, so it can't fill up the disk.
+const MAX_DOWNLOADS: usize = 100;
+const MAX_DOWNLOAD_BYTES: u64 = 1024 * 1024 * 1024;
+
 /// Open a viewer window and connect to the server
 ///
+/// Before a url from the server is opened, the user is asked.
+///
 /// Logs to stdout if you call tracing_subscriber::fmt::init() before run()
 /// and run your app with `RUST_LOG=debug`.
+///
+/// Files the server sends are refused.
 pub fn run(url: String) {
+    run_client(new_client(url), None);
+}
+
+/// Like [`run`], but watch the session of another client instead of having our own.
+///
+/// Our input is ignored until the driver hands the session off to us.
+pub fn spectate(url: String, client_id: eterm::ClientId) {
+    let client = new_client(url);
+    client.spectate(Some(client_id));
+    run_client(client, None);
+}
+
+fn new_client(url: String) -> eterm::Client {
+    let mut client = eterm::Client::with_capabilities(url, capabilities());
+    client.set_open_url_policy(eterm::OpenUrlPolicy::Ask(Default::default()));
+    client
+}
+
+/// What the viewer tells the server about itself before it knows its window,
+/// for [`eterm::Client::with_capabilities`].
+pub fn capabilities() -> eterm::Capabilities {
+    eterm::Capabilities::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Like [`run`], but with a [`eterm::Client`] you have already set up,
+/// e.g. with [`eterm::Client::set_name`].
+///
+/// With [`eterm::OpenUrlPolicy::Ask`], the viewer shows a prompt for each url
+/// (the default policy of [`eterm::Client`] blocks all urls).
+///
+/// Files dropped onto the window are uploaded to the server.
+/// Files the server sends are saved in `download_dir` (up to a limit per session),
+/// or refused if it is `None`.
+///
+/// The viewer fills in its own name, version, scale and texture size in
+/// [`eterm::Client::capabilities`], keeping the rest (e.g. the theme).
+/// Create the client with [`capabilities`] so the server has the rest from the start.
+pub fn run_client(mut client: eterm::Client, download_dir: Option<PathBuf>) {
     let event_loop = EventLoopBuilder::with_user_event().build();
     let display = create_display(&event_loop);
     let mut egui_glium = egui_glium::EguiGlium::new(&display, &event_loop);
     let pixels_per_point = egui_glium.egui_winit.pixels_per_point();
-    let mut last_sent_input = None;
+
+    client.set_capabilities(eterm::Capabilities {
+        viewer_name: env!("CARGO_PKG_NAME").to_owned(),
+        viewer_version: env!("CARGO_PKG_VERSION").to_owned(),
+        pixels_per_point: Some(pixels_per_point),
+        max_texture_side: Some(egui_glium.painter.max_texture_side()),
+        ..client.capabilities()
+    });
+
     let mut last_frame_index = 0;
+    let mut url_prompt = UrlPrompt::new(&display);
+    let mut prompt_visible = false;
+    let mut title = TITLE.to_owned();
+    let mut ime_allowed = false;
+    let mut downloads = Downloads::default();
+    client.set_downloads_enabled(download_dir.is_some());
 
-    let mut client = eterm::Client::new(url);
+    // Repainted under the prompt until the server sends a new frame:
+    let mut clipped_primitives = vec![];
+
+    // Repaint when the server sends us something new:
+    let event_loop_proxy = event_loop.create_proxy();
+    client.set_wake_callback(move || {
+        event_loop_proxy.send_event(()).ok();
+    });
 
     // work arround for init of fonts
     {
@@ -35,8 +109,8 @@ pub fn run(url: String) {
             egui::SidePanel::left("").show(egui_ctx, |_| {});
         });
     }
-    // This event loop might send the user input (e.g. mouse movement) 100 times a second
-    // and the server might send new frames at a rate of 30 times a second.
+    // This event loop sends the user input (e.g. mouse movement) whenever there is some,
+    // and paints whenever the server sends a new frame (which wakes us up with a user event).
     // Thus the frame rate is dictated by the server but the user input update rate is dictated
     // by this event_loop.
     event_loop.run(move |event, _, control_flow| {
@@ -47,15 +121,39 @@ pub fn run(url: String) {
                 .take_egui_input(display.gl_window().window());
             raw_input.pixels_per_point = Some(pixels_per_point);
 
-            // Send input to server
-            let input_changed = last_sent_input.as_ref() != Some(&raw_input);
-            if input_changed {
-                client.send_input(raw_input.clone());
-                last_sent_input = Some(raw_input);
-            }
+            // While the prompt is open, it gets all the input:
+            let prompt_input = if url_prompt.is_open() {
+                raw_input
+            } else {
+                let prompt_input = egui::RawInput {
+                    screen_rect: raw_input.screen_rect,
+                    pixels_per_point: raw_input.pixels_per_point,
+                    ..Default::default()
+                };
+
+                // Send input to server (only what changed is sent over the network)
+                client.send_input(raw_input);
+
+                prompt_input
+            };
 
             // Check if server has sent a new frame
             let new_frame = client.update();
+            url_prompt.push(client.take_url_requests());
+
+            for file in client.take_downloads() {
+                if let Some(download_dir) = &download_dir {
+                    downloads.save(download_dir, &file);
+                }
+            }
+            let new_title = title_with_progress(&client.transfers());
+            if new_title != title {
+                display.gl_window().window().set_title(&new_title);
+                title = new_title;
+            }
+
+            let mut textures_delta = Default::default();
+            let mut repaint = url_prompt.is_open() || prompt_visible;
 
             // Always paint a frame rate when there is one
             if let Some(frame) = new_frame {
@@ -63,17 +161,30 @@ pub fn run(url: String) {
                     frame_index,
                     platform_output,
                     clipped_net_mesh,
-                    textures_delta,
+                    textures_delta: frame_textures_delta,
                 } = frame;
 
                 last_frame_index = frame_index;
 
+                // winit only sends IME events (composition of CJK text etc) if we allow it,
+                // which we do while a text field on the server has focus:
+                if platform_output.text_cursor_pos.is_some() != ime_allowed {
+                    ime_allowed = !ime_allowed;
+                    display.gl_window().window().set_ime_allowed(ime_allowed);
+                }
+
                 egui_glium.egui_winit.handle_platform_output(
                     display.gl_window().window(),
                     &egui_glium.egui_ctx,
                     platform_output,
                 );
 
+                clipped_primitives = into_clipped_primitives(clipped_net_mesh);
+                textures_delta = frame_textures_delta;
+                repaint = true;
+            }
+
+            if repaint {
                 // paint the frame from the server:
                 use glium::Surface as _;
                 let mut target = display.draw();
@@ -85,8 +196,6 @@ pub fn run(url: String) {
                     CLEAR_COLOR[3],
                 );
 
-                let clipped_primitives = into_clipped_primitives(clipped_net_mesh);
-
                 egui_glium.painter.paint_and_update_textures(
                     &display,
                     &mut target,
@@ -95,12 +204,24 @@ pub fn run(url: String) {
                     &textures_delta,
                 );
 
+                prompt_visible = url_prompt.is_open();
+                if prompt_visible {
+                    let (platform_output, needs_repaint) =
+                        url_prompt.show(&display, &mut target, prompt_input);
+                    egui_glium.egui_winit.handle_platform_output(
+                        display.gl_window().window(),
+                        &egui_glium.egui_ctx,
+                        platform_output,
+                    );
+                    if needs_repaint {
+                        display.gl_window().window().request_redraw();
+                    }
+                }
+
                 target.finish().unwrap();
             }
 
-            display.gl_window().window().request_redraw();
             *control_flow = glutin::event_loop::ControlFlow::Wait;
-            std::thread::sleep(std::time::Duration::from_millis(10));
         };
 
         match event {
@@ -110,6 +231,10 @@ pub fn run(url: String) {
             glutin::event::Event::RedrawEventsCleared if cfg!(windows) => redraw(),
             glutin::event::Event::RedrawRequested(_) if !cfg!(windows) => redraw(),
 
+            glutin::event::Event::UserEvent(()) => {
+                display.gl_window().window().request_redraw();
+            }
+
             glutin::event::Event::WindowEvent { event, .. } => {
                 use glutin::event::WindowEvent;
                 if matches!(event, WindowEvent::CloseRequested | WindowEvent::Destroyed) {
@@ -133,7 +258,7 @@ fn create_display(event_loop: &glutin::event_loop::EventLoop<()>) -> glium::Disp
             width: 800.0,
             height: 600.0,
         })
-        .with_title("eterm viewer");
+        .with_title(TITLE);
 
     let context_builder = glutin::ContextBuilder::new()
         .with_depth_buffer(0)
@@ -144,6 +269,70 @@ fn create_display(event_loop: &glutin::event_loop::EventLoop<()>) -> glium::Disp
 
     glium::Display::new(window_builder, context_builder, event_loop).unwrap()
 }
+/// What we have saved from the server so far.
+#[derive(Default)]
+struct Downloads {
+    num_files: usize,
+    num_bytes: u64,
+}
+
+impl Downloads {
+    fn save(&mut self, download_dir: &Path, file: &eterm::transfer::ReceivedFile) {
+        let num_bytes = self.num_bytes + file.bytes.len() as u64;
+        if self.num_files >= MAX_DOWNLOADS || num_bytes > MAX_DOWNLOAD_BYTES {
+            tracing::warn!(
+                "Not saving {:?}: the server has sent too much already",
+                file.name
+            );
+            return;
+        }
+        self.num_files += 1;
+        self.num_bytes = num_bytes;
+        save_download(download_dir, file);
+    }
+}
+
+/// Save a file from the server, without overwriting anything.
+fn save_download(download_dir: &Path, file: &eterm::transfer::ReceivedFile) {
+    let name = PathBuf::from(file.safe_file_name());
+    let mut path = download_dir.join(&name);
+    for i in 1.. {
+        if !path.exists() {
+            break;
+        }
+        let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+        path = match name.extension() {
+            Some(extension) => {
+                download_dir.join(format!("{} ({}).{}", stem, i, extension.to_string_lossy()))
+            }
+            None => download_dir.join(format!("{} ({})", stem, i)),
+        };
+    }
+
+    match std::fs::write(&path, &file.bytes) {
+        Ok(()) => tracing::info!("Saved {:?} to {}", file.name, path.display()),
+        Err(err) => tracing::error!("Failed to save {}: {}", path.display(), err),
+    }
+}
+
+/// Show the progress of uploads and downloads in the window title.
+fn title_with_progress(transfers: &[eterm::transfer::TransferProgress]) -> String {
+    let mut title = TITLE.to_owned();
+    for transfer in transfers {
+        let verb = match transfer.id.direction {
+            eterm::transfer::Direction::Upload => "uploading",
+            eterm::transfer::Direction::Download => "downloading",
+        };
+        title += &format!(
+            " – {} {} {:.0}%",
+            verb,
+            transfer.name,
+            100.0 * transfer.fraction()
+        );
+    }
+    title
+}
+
 fn into_clipped_primitives(meshes: Vec<ClippedNetMesh>) -> Vec<ClippedPrimitive> {
     meshes.into_iter().map(to_clipped_primitve).collect()
 }
@@ -6,12 +6,83 @@ struct Arguments {
     /// which server to connect to, e.g. `127.0.0.1:8505`.
     #[argh(option)]
     url: String,
+
+    /// watch the session of this client id instead of having our own.
+    #[argh(option)]
+    spectate: Option<eterm::ClientId>,
+
+    /// what to call you when the server shows your pointer to others.
+    #[argh(option)]
+    name: Option<String>,
+
+    /// tell the server you prefer `dark` or `light` mode.
+    #[argh(option)]
+    theme: Option<String>,
+
+    /// don't let the server read or write your clipboard.
+    #[argh(switch)]
+    no_clipboard: bool,
+
+    /// what to do when the server wants to open a url: `ask` (default), `block` or `allow`.
+    /// Urls on the allowlist are opened without asking.
+    #[argh(option, default = "String::from(\"ask\")")]
+    open_urls: String,
+
+    /// url scheme to allowlist, e.g. `https`. Can be repeated.
+    #[argh(option)]
+    allow_url_scheme: Vec<String>,
+
+    /// url host to allowlist, e.g. `docs.rs`. Can be repeated.
+    #[argh(option)]
+    allow_url_host: Vec<String>,
+
+    /// where to save files the server sends. Without it, downloads are refused.
+    #[argh(option)]
+    download_dir: Option<std::path::PathBuf>,
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     // Log to stdout (if you run with `RUST_LOG=debug`).
     tracing_subscriber::fmt::init();
 
     let opt: Arguments = argh::from_env();
-    eterm_viewer::run(opt.url)
+
+    let allowlist = eterm::UrlAllowlist {
+        schemes: opt.allow_url_scheme,
+        hosts: opt.allow_url_host,
+    };
+    let open_url_policy = match opt.open_urls.as_str() {
+        "ask" => eterm::OpenUrlPolicy::Ask(allowlist),
+        "block" => eterm::OpenUrlPolicy::Allowlist(allowlist),
+        "allow" => eterm::OpenUrlPolicy::Allow,
+        other => anyhow::bail!(
+            "Unknown --open-urls {:?}; expected ask, block or allow",
+            other
+        ),
+    };
+
+    let theme = match opt.theme.as_deref() {
+        None => None,
+        Some("dark") => Some(eterm::capabilities::Theme::Dark),
+        Some("light") => Some(eterm::capabilities::Theme::Light),
+        Some(other) => anyhow::bail!("Unknown --theme {:?}; expected dark or light", other),
+    };
+
+    let mut client = eterm::Client::with_capabilities(
+        opt.url,
+        eterm::Capabilities {
+            theme,
+            ..eterm_viewer::capabilities()
+        },
+    );
+    client.set_open_url_policy(open_url_policy);
+    if let Some(name) = opt.name {
+        client.set_name(name);
+    }
+    client.set_clipboard_enabled(!opt.no_clipboard);
+    if opt.spectate.is_some() {
+        client.spectate(opt.spectate);
+    }
+    eterm_viewer::run_client(client, opt.download_dir);
+    Ok(())
 }
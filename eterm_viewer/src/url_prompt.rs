@@ -0,0 +1,124 @@
+use egui::output::OpenUrl;
+use std::collections::VecDeque;
+
+/// Requests beyond this many are dropped, so a server can't bury the user in prompts.
+const MAX_QUEUED_REQUESTS: usize = 8;
+
+/// Asks the user whether to open the urls the server wants us to open
+/// (with [`eterm::OpenUrlPolicy::Ask`]).
+///
+/// Has its own egui context and painter, so it doesn't mix with the textures of the server.
+/// It is painted on top of the server frame, and gets all our input while it is shown.
+pub(crate) struct UrlPrompt {
+    egui_ctx: egui::Context,
+    painter: egui_glium::Painter,
+
+    /// Asked one at a time, oldest first.
+    requests: VecDeque<OpenUrl>,
+
+    /// The user chose to block all urls from this server.
+    block_all: bool,
+}
+
+impl UrlPrompt {
+    pub fn new(display: &glium::Display) -> Self {
+        Self {
+            egui_ctx: Default::default(),
+            painter: egui_glium::Painter::new(display),
+            requests: Default::default(),
+            block_all: false,
+        }
+    }
+
+    /// Queue requests, skipping those that are already queued.
+    pub fn push(&mut self, requests: Vec<OpenUrl>) {
+        for request in requests {
+            if self.block_all {
+                tracing::info!("Blocked the server from opening {:?}", request.url);
+            } else if self.requests.iter().any(|queued| queued.url == request.url) {
+                // Already asking
+            } else if self.requests.len() < MAX_QUEUED_REQUESTS {
+                self.requests.push_back(request);
+            } else {
+                tracing::warn!(
+                    "Too many urls waiting to be opened; dropped {:?}",
+                    request.url
+                );
+            }
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    /// Paint the prompt for the oldest request.
+    ///
+    /// If the user says yes, the url is in the returned [`egui::PlatformOutput::open_url`].
+    /// The `bool` is true if we need to paint again soon.
+    pub fn show(
+        &mut self,
+        display: &glium::Display,
+        target: &mut glium::Frame,
+        raw_input: egui::RawInput,
+    ) -> (egui::PlatformOutput, bool) {
+        let pixels_per_point = raw_input.pixels_per_point.unwrap_or(1.0);
+        let mut answer = None;
+        let mut block_all = false;
+
+        let full_output = self.egui_ctx.run(raw_input, |egui_ctx| {
+            let request = match self.requests.front() {
+                Some(request) => request,
+                None => return,
+            };
+
+            egui::Window::new("Open link?")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+                .show(egui_ctx, |ui| {
+                    ui.label("The server wants to open:");
+                    ui.monospace(&request.url);
+                    ui.horizontal(|ui| {
+                        if ui.button("Open").clicked() {
+                            answer = Some(true);
+                        }
+                        if ui.button("Don't open").clicked()
+                            || ui.input().key_pressed(egui::Key::Escape)
+                        {
+                            answer = Some(false);
+                        }
+                        if ui.button("Block all from this server").clicked() {
+                            answer = Some(false);
+                            block_all = true;
+                        }
+                    });
+                });
+        });
+
+        let mut platform_output = full_output.platform_output;
+        if let Some(open) = answer {
+            let request = self.requests.pop_front();
+            if open {
+                platform_output.open_url = request;
+            }
+        }
+        if block_all {
+            tracing::info!("Blocking all urls from the server");
+            self.block_all = true;
+            self.requests.clear();
+        }
+
+        let clipped_primitives = self.egui_ctx.tessellate(full_output.shapes);
+        self.painter.paint_and_update_textures(
+            display,
+            target,
+            pixels_per_point,
+            &clipped_primitives,
+            &full_output.textures_delta,
+        );
+
+        let needs_repaint = answer.is_some() || full_output.repaint_after.is_zero();
+        (platform_output, needs_repaint)
+    }
+}